@@ -0,0 +1,73 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use regex::bytes::Regex;
+
+#[allow(dead_code)]
+#[path = "../../src/day3/tokenizer.rs"]
+mod tokenizer;
+
+use tokenizer::{read_calls, Call, Spanned, Tokenizer};
+
+const STANDARD: [(&str, usize); 3] = [("mul", 2), ("do", 0), ("don't", 0)];
+const EXTENDED: [(&str, usize); 7] = [
+    ("mul", 2),
+    ("add", 2),
+    ("sub", 2),
+    ("do", 0),
+    ("don't", 0),
+    ("begin", 0),
+    ("end", 0),
+];
+
+fn find_calls(pattern: &str, input: &[u8]) -> Vec<Spanned<Call>> {
+    let regex = Regex::new(pattern).unwrap();
+    let text = |bytes: &[u8]| std::str::from_utf8(bytes).unwrap().to_string();
+    regex
+        .captures_iter(input)
+        .map(|captures| Spanned {
+            item: match captures.get(1) {
+                Some(opcode) => Call::new(
+                    &text(opcode.as_bytes()),
+                    vec![
+                        text(&captures[2]).parse().unwrap(),
+                        text(&captures[3]).parse().unwrap(),
+                    ],
+                ),
+                None => Call::new(&text(&captures[4]), vec![]),
+            },
+            span: captures.get(0).unwrap().range(),
+        })
+        .collect()
+}
+
+// The first byte picks a chunk size, the rest is the corrupted memory.
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk_size, input)) = data.split_first() else {
+        return;
+    };
+
+    for (opcodes, pattern) in [
+        (
+            &STANDARD[..],
+            r"(mul)\(([0-9]{1,3}),([0-9]{1,3})\)|(do|don't)\(\)",
+        ),
+        (
+            &EXTENDED[..],
+            r"(mul|add|sub)\(([0-9]{1,3}),([0-9]{1,3})\)|(do|don't|begin|end)\(\)",
+        ),
+    ] {
+        let expected = find_calls(pattern, input);
+        assert_eq!(
+            read_calls(input, Tokenizer::new(opcodes.iter().copied())).unwrap(),
+            expected
+        );
+
+        let mut tokenizer = Tokenizer::new(opcodes.iter().copied());
+        let mut calls = vec![];
+        for chunk in input.chunks(chunk_size.max(1) as usize) {
+            tokenizer.feed(chunk, &mut calls);
+        }
+        assert_eq!(calls, expected);
+    }
+});
@@ -40,7 +40,7 @@ pub(crate) fn day1(input_filename: &str) {
         .iter()
         .map(|&lnum| {
             let count_in_right = &right.iter().filter(|&&rnum| rnum == lnum).count();
-            lnum * (count_in_right.clone() as u32)
+            lnum * (*count_in_right as u32)
         })
         .sum();
 
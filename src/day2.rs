@@ -1,91 +1,364 @@
-use std::{cell::RefCell, collections::HashSet, fs};
+use std::{cmp::Ordering, collections::HashSet, fs};
 
-pub(crate) fn day2(input_filename: &str) {
+use clap::ValueEnum;
+
+pub(crate) fn day2(input_filename: &str, damping: usize, explain: Option<ExplainFormat>) {
     // read and parse the input file
 
     let contents = fs::read_to_string(input_filename).expect("could not read file");
 
+    if let Some(format) = explain {
+        print_explanation(&diagnose_reports(&contents, damping), format);
+        if format == ExplainFormat::Json {
+            return;
+        }
+        println!();
+    }
+
     let num_safe_reports = count_safe_reports(&contents);
-    let num_safe_reports_with_damping = count_safe_reports_with_damping(&contents);
+    let num_safe_reports_with_damping = count_safe_reports_with_damping(&contents, damping);
 
     println!("number of safe reports: {}", num_safe_reports);
     println!("    safe after damping: {}", num_safe_reports_with_damping);
 }
 
+fn parse_reports(input: &str) -> impl Iterator<Item = Vec<i64>> + '_ {
+    input.lines().map(|line| {
+        line.split(' ')
+            .map(|num| num.parse::<i64>().unwrap())
+            .collect::<Vec<i64>>()
+    })
+}
+
 fn count_safe_reports(input: &str) -> usize {
-    input
-        .lines()
-        .map(|line| {
-            line.split(' ')
-                .map(|num| num.parse::<i8>().unwrap())
-                .collect::<Vec<i8>>()
-        })
+    parse_reports(input)
         .filter(|report| get_violations(report).is_empty())
         .count()
 }
 
-// TODO finish later
-fn count_safe_reports_with_damping(input: &str) -> usize {
-    input
-        .lines()
-        .map(|line| {
-            line.split(' ')
-                .map(|num| num.parse::<i8>().unwrap())
-                .collect::<Vec<i8>>()
-        })
-        .filter(|report| is_report_safe_with_damping(&report))
+fn count_safe_reports_with_damping(input: &str, damping: usize) -> usize {
+    parse_reports(input)
+        .filter(|report| is_report_safe_with_damping(report, damping))
         .count()
 }
 
-fn is_report_safe_with_damping(report: &Vec<i8>) -> bool {
-    let violations = get_violations(report).drain().collect::<Vec<_>>();
-
-    if violations.is_empty() {
+/// Checks whether a report is safe once the Problem Dampener has removed at
+/// most `damping` levels from it.
+fn is_report_safe_with_damping(report: &[i64], damping: usize) -> bool {
+    // removing all but one level always leaves a safe report
+    if report.len() <= damping.saturating_add(1) {
         return true;
     }
 
-    let is_safe_after_damping = (0..report.len())
-        .map(|i| {
-            let mut report = report.to_owned();
-            report.remove(i);
-            get_violations(&report)
-        })
-        .any(|violations| violations.is_empty());
+    [Ordering::Greater, Ordering::Less]
+        .iter()
+        .any(|&direction| min_removals(report, direction, damping) <= damping)
+}
 
-    if !is_safe_after_damping {
-        println!("unsafe after damping: {:?}", report);
+/// Counts the levels that need removing so that every remaining step moves
+/// in `direction` (`Greater` for increasing, `Less` for decreasing) by 1 to 3.
+/// The count is the fewest possible whenever that is at most `damping`;
+/// otherwise it is only known to be more than `damping`.
+///
+/// `removed[i]` is the fewest removals for a safe run which keeps level `i` as
+/// its last level. Anything more than `damping + 1` levels back cannot precede
+/// `i` without blowing the budget, so each level only looks at that window
+/// and the whole thing is O(n * damping) without copying the report.
+fn min_removals(report: &[i64], direction: Ordering, damping: usize) -> usize {
+    let n = report.len();
+    let mut removed = Vec::with_capacity(n);
+
+    for i in 0..n {
+        // starting the run at `i` means dropping everything before it
+        let mut best = i;
+        for j in i.saturating_sub(damping.saturating_add(1))..i {
+            if is_safe_step(report[j], report[i], direction) {
+                best = best.min(removed[j] + (i - j - 1));
+            }
+        }
+        removed.push(best);
     }
 
-    is_safe_after_damping
+    // ...and ending it at `i` means dropping everything after it
+    removed
+        .iter()
+        .enumerate()
+        .map(|(i, &r)| r + (n - 1 - i))
+        .min()
+        .unwrap_or(0)
 }
 
-fn get_violations(report: &Vec<i8>) -> HashSet<usize> {
-    let (violation, _) = (1..report.len())
-        .into_iter()
-        .map(|i| (i, report[i - 1] - report[i]))
-        .fold(
-            (RefCell::new(HashSet::new()), None),
-            |(violating_idx, last_diff): (RefCell<HashSet<usize>>, Option<i8>),
-             (i, current_diff)| {
-                if !(1..4i8).contains(&current_diff.abs()) {
-                    violating_idx.borrow_mut().insert(i);
-                }
+/// Finds the first level whose removal leaves every remaining step moving in
+/// `direction` by 1 to 3, in one pass rather than re-checking the report once
+/// per level.
+fn first_single_removal(report: &[i64], direction: Ordering) -> Option<usize> {
+    let n = report.len();
+    let safe = |i: usize, j: usize| is_safe_step(report[i], report[j], direction);
+    // `safe_before[i]`: the levels before `i` are safe on their own, and
+    // `safe_after[i]`: so are the levels after `i`
+    let mut safe_before = vec![true; n];
+    for i in 2..n {
+        safe_before[i] = safe_before[i - 1] && safe(i - 2, i - 1);
+    }
+    let mut safe_after = vec![true; n];
+    for i in (0..n.saturating_sub(2)).rev() {
+        safe_after[i] = safe_after[i + 1] && safe(i + 1, i + 2);
+    }
 
-                if let Some(last_diff) = last_diff {
-                    if last_diff.signum() != current_diff.signum() {
-                        violating_idx.borrow_mut().insert(i);
-                    }
-                }
+    (0..n)
+        .find(|&i| safe_before[i] && safe_after[i] && (i == 0 || i == n - 1 || safe(i - 1, i + 1)))
+}
 
-                (violating_idx, Some(current_diff))
-            },
-        );
+fn is_safe_step(from: i64, to: i64, direction: Ordering) -> bool {
+    let step = Step::between(from, to);
+    step.direction == direction && (1..4).contains(&step.size)
+}
+
+/// The change from one level to the next, kept as a direction and a size so
+/// that levels at opposite ends of the `i64` range cannot overflow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Step {
+    direction: Ordering,
+    size: u64,
+}
+
+impl Step {
+    fn between(from: i64, to: i64) -> Self {
+        Self {
+            direction: to.cmp(&from),
+            size: from.abs_diff(to),
+        }
+    }
+}
 
-    violation.take()
+fn get_violations(report: &[i64]) -> HashSet<usize> {
+    find_violations(report)
+        .iter()
+        .map(|violation| violation.index)
+        .collect()
+}
+
+/// Lists every rule broken by the step into each level, in level order. A
+/// level can break more than one rule, e.g. a big jump the wrong way.
+fn find_violations(report: &[i64]) -> Vec<Violation> {
+    let mut violations = vec![];
+    let mut last_step: Option<Step> = None;
+
+    for i in 1..report.len() {
+        let current_step = Step::between(report[i - 1], report[i]);
+
+        if current_step.size == 0 {
+            violations.push(Violation::new(i, ViolationKind::ZeroStep));
+        } else if current_step.size > 3 {
+            violations.push(Violation::new(i, ViolationKind::StepTooLarge));
+        }
+
+        if let Some(last_step) = last_step {
+            if last_step.direction != current_step.direction {
+                violations.push(Violation::new(i, ViolationKind::DirectionChange));
+            }
+        }
+
+        last_step = Some(current_step);
+    }
+
+    violations
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Violation {
+    /// Index of the level the offending step leads into
+    index: usize,
+    kind: ViolationKind,
+}
+
+impl Violation {
+    fn new(index: usize, kind: ViolationKind) -> Self {
+        Self { index, kind }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ViolationKind {
+    StepTooLarge,
+    ZeroStep,
+    DirectionChange,
+}
+
+impl ViolationKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ViolationKind::StepTooLarge => "step_too_large",
+            ViolationKind::ZeroStep => "zero_step",
+            ViolationKind::DirectionChange => "direction_change",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ReportDiagnostics {
+    levels: Vec<i64>,
+    violations: Vec<Violation>,
+    /// Whether the report is safe within the configured damping budget
+    safe_after_damping: bool,
+    /// The first level whose removal alone makes the report safe
+    fixed_by_removing: Option<usize>,
+}
+
+impl ReportDiagnostics {
+    fn new(levels: Vec<i64>, damping: usize) -> Self {
+        let violations = find_violations(&levels);
+        let safe_after_damping = is_report_safe_with_damping(&levels, damping);
+        let fixed_by_removing = if violations.is_empty() {
+            None
+        } else {
+            [Ordering::Greater, Ordering::Less]
+                .iter()
+                .filter_map(|&direction| first_single_removal(&levels, direction))
+                .min()
+        };
+
+        Self {
+            levels,
+            violations,
+            safe_after_damping,
+            fixed_by_removing,
+        }
+    }
+
+    fn is_safe(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    fn status(&self) -> &'static str {
+        if self.is_safe() {
+            "safe"
+        } else if self.safe_after_damping {
+            "damped"
+        } else {
+            "unsafe"
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let levels = self
+            .levels
+            .iter()
+            .map(|level| level.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let violations = self
+            .violations
+            .iter()
+            .map(|v| format!("{{\"index\":{},\"kind\":\"{}\"}}", v.index, v.kind.name()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let fixed_by_removing = self
+            .fixed_by_removing
+            .map_or("null".to_string(), |i| i.to_string());
+
+        format!(
+            "{{\"levels\":[{}],\"safe\":{},\"safe_after_damping\":{},\"violations\":[{}],\"fixed_by_removing\":{}}}",
+            levels,
+            self.is_safe(),
+            self.safe_after_damping,
+            violations,
+            fixed_by_removing
+        )
+    }
+}
+
+fn diagnose_reports(input: &str, damping: usize) -> Vec<ReportDiagnostics> {
+    parse_reports(input)
+        .map(|report| ReportDiagnostics::new(report, damping))
+        .collect()
+}
+
+fn print_explanation(diagnostics: &[ReportDiagnostics], format: ExplainFormat) {
+    match format {
+        ExplainFormat::Table => {
+            print_row("levels", "status", "violations", "fix");
+            for diag in diagnostics {
+                let levels = diag
+                    .levels
+                    .iter()
+                    .map(|level| level.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let violations = diag
+                    .violations
+                    .iter()
+                    .map(|v| format!("{}:{}", v.index, v.kind.name()))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let violations = if violations.is_empty() {
+                    "-".to_string()
+                } else {
+                    violations
+                };
+                let fix = diag
+                    .fixed_by_removing
+                    .map_or("-".to_string(), |i| format!("remove {}", i));
+                print_row(&levels, diag.status(), &violations, &fix);
+            }
+        }
+        ExplainFormat::Json => {
+            let reports = diagnostics
+                .iter()
+                .map(|diag| diag.to_json())
+                .collect::<Vec<_>>()
+                .join(",\n  ");
+            println!("[\n  {}\n]", reports);
+        }
+    }
 }
 
+fn print_row(levels: &str, status: &str, violations: &str, fix: &str) {
+    println!("{:<30} {:<7} {:<60} {}", levels, status, violations, fix);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum ExplainFormat {
+    Table,
+    Json,
+}
+
+#[cfg(test)]
 mod tests {
-    use crate::day2::{count_safe_reports, count_safe_reports_with_damping};
+    use crate::day2::{
+        count_safe_reports, count_safe_reports_with_damping, diagnose_reports, get_violations,
+        is_report_safe_with_damping, ReportDiagnostics, Violation, ViolationKind,
+    };
+
+    /// Reference implementation: try every way of removing up to `damping` levels.
+    fn is_safe_brute_force(report: &[i64], damping: usize) -> bool {
+        if get_violations(report).is_empty() {
+            return true;
+        }
+
+        damping > 0
+            && (0..report.len()).any(|i| {
+                let mut report = report.to_owned();
+                report.remove(i);
+                is_safe_brute_force(&report, damping - 1)
+            })
+    }
+
+    /// All reports of length `len` using only the given levels.
+    fn all_reports(len: usize, levels: &[i64]) -> Vec<Vec<i64>> {
+        (0..len).fold(vec![vec![]], |reports, _| {
+            reports
+                .iter()
+                .flat_map(|report| {
+                    levels.iter().map(move |&level| {
+                        let mut report = report.clone();
+                        report.push(level);
+                        report
+                    })
+                })
+                .collect()
+        })
+    }
 
     #[test]
     fn test_example_data() {
@@ -124,7 +397,7 @@ mod tests {
 1 3 2 4 5
 8 6 4 4 1
 1 3 6 7 9";
-        assert_eq!(count_safe_reports_with_damping(input), 4);
+        assert_eq!(count_safe_reports_with_damping(input, 1), 4);
     }
 
     #[test]
@@ -133,6 +406,158 @@ mod tests {
         //      2   2   1  -3
 
         let input = "74 76 78 79 76";
-        assert_eq!(count_safe_reports_with_damping(input), 1);
+        assert_eq!(count_safe_reports_with_damping(input, 1), 1);
+    }
+
+    #[test]
+    fn damping_matches_brute_force() {
+        let levels = [1, 2, 3, 4, 5, 9];
+        for len in 0..=6 {
+            for report in all_reports(len, &levels) {
+                assert_eq!(
+                    is_report_safe_with_damping(&report, 1),
+                    is_safe_brute_force(&report, 1),
+                    "{:?}",
+                    report
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn deeper_damping_matches_brute_force() {
+        let levels = [1, 2, 4, 5, 9];
+        for len in 0..=5 {
+            for report in all_reports(len, &levels) {
+                for damping in [0, 2, 3] {
+                    assert_eq!(
+                        is_report_safe_with_damping(&report, damping),
+                        is_safe_brute_force(&report, damping),
+                        "{:?} with damping {}",
+                        report,
+                        damping
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn example_with_more_damping() {
+        let input = "7 6 4 2 1
+1 2 7 8 9
+9 7 6 2 1
+1 3 2 4 5
+8 6 4 4 1
+1 3 6 7 9";
+        assert_eq!(count_safe_reports_with_damping(input, 0), 2);
+        // 1 2 7 8 9 -> drop 1 2; 9 7 6 2 1 -> drop 2 1
+        assert_eq!(count_safe_reports_with_damping(input, 2), 6);
+    }
+
+    #[test]
+    fn unlimited_damping() {
+        let input = "7 6 4 2 1
+1 2 7 8 9
+9 7 6 2 1";
+        assert_eq!(count_safe_reports_with_damping(input, usize::MAX), 3);
+    }
+
+    #[test]
+    fn diagnostics_for_example() {
+        let input = "7 6 4 2 1
+1 2 7 8 9
+1 3 2 4 5
+8 6 4 4 1";
+        let diagnostics = diagnose_reports(input, 1);
+
+        assert!(diagnostics[0].is_safe());
+        assert_eq!(diagnostics[0].fixed_by_removing, None);
+
+        assert_eq!(
+            diagnostics[1].violations,
+            vec![Violation::new(2, ViolationKind::StepTooLarge)]
+        );
+        assert!(!diagnostics[1].safe_after_damping);
+        assert_eq!(diagnostics[1].fixed_by_removing, None);
+
+        assert_eq!(
+            diagnostics[2].violations,
+            vec![
+                Violation::new(2, ViolationKind::DirectionChange),
+                Violation::new(3, ViolationKind::DirectionChange)
+            ]
+        );
+        assert!(diagnostics[2].safe_after_damping);
+        assert_eq!(diagnostics[2].fixed_by_removing, Some(1));
+
+        assert_eq!(
+            diagnostics[3].violations,
+            vec![
+                Violation::new(3, ViolationKind::ZeroStep),
+                Violation::new(3, ViolationKind::DirectionChange),
+                Violation::new(4, ViolationKind::DirectionChange)
+            ]
+        );
+        assert_eq!(diagnostics[3].fixed_by_removing, Some(2));
+    }
+
+    #[test]
+    fn fix_matches_brute_force() {
+        let levels = [1, 2, 3, 4, 5, 9];
+        for len in 0..=6 {
+            for report in all_reports(len, &levels) {
+                let expected = if get_violations(&report).is_empty() {
+                    None
+                } else {
+                    (0..report.len()).find(|&i| {
+                        let mut report = report.clone();
+                        report.remove(i);
+                        get_violations(&report).is_empty()
+                    })
+                };
+                assert_eq!(
+                    ReportDiagnostics::new(report.clone(), 1).fixed_by_removing,
+                    expected,
+                    "{:?}",
+                    report
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn diagnostics_as_json() {
+        let diagnostics = diagnose_reports("1 3 2 4 5", 1);
+        assert_eq!(
+            diagnostics[0].to_json(),
+            r#"{"levels":[1,3,2,4,5],"safe":false,"safe_after_damping":true,"violations":[{"index":2,"kind":"direction_change"},{"index":3,"kind":"direction_change"}],"fixed_by_removing":1}"#
+        );
+    }
+
+    #[test]
+    fn wide_levels() {
+        let input = "1000 1002 1005 1006
+-300 -297 -295 -294
+9223372036854775807 9223372036854775806 9223372036854775804
+-9223372036854775808 -9223372036854775806 -9223372036854775805
+-9223372036854775808 9223372036854775807
+9223372036854775807 -9223372036854775808 9223372036854775806";
+        assert_eq!(count_safe_reports(input), 4);
+        // the last report is safe once the jump down to i64::MIN is dropped
+        assert_eq!(count_safe_reports_with_damping(input, 1), 6);
+    }
+
+    #[test]
+    fn wide_level_diagnostics() {
+        let diagnostics = diagnose_reports("-9223372036854775808 9223372036854775807 0", 1);
+        assert_eq!(
+            diagnostics[0].violations,
+            vec![
+                Violation::new(1, ViolationKind::StepTooLarge),
+                Violation::new(2, ViolationKind::StepTooLarge),
+                Violation::new(2, ViolationKind::DirectionChange)
+            ]
+        );
     }
 }
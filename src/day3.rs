@@ -1,40 +1,483 @@
+#[cfg(test)]
 use regex::Regex;
-use std::fs;
+use std::fs::{self, File};
 
-pub(crate) fn day3(input_filename: &str) {
-    let contents = fs::read_to_string(input_filename).expect("could not read file");
-    let tokens = find_tokens(&contents);
+mod tokenizer;
+mod trace;
+mod vm;
 
-    let mut mul_enabled = true;
-    let mut acc: i64 = 0;
+use tokenizer::{read_calls, Call, Spanned};
+use trace::{format_trace, highlight, trace};
+pub(crate) use vm::Accumulate;
+use vm::{Machine, Registry};
 
-    for tok in tokens {
-        match tok {
-            Token::Do => mul_enabled = true,
-            Token::Dont => mul_enabled = false,
-            Token::Mul(a, b) => if mul_enabled {
-                acc += a * b;
-            }
+pub(crate) fn day3(
+    input_filename: &str,
+    extended: bool,
+    accumulate: Accumulate,
+    show_trace: bool,
+    show_highlight: bool,
+) {
+    let registry = if extended {
+        Registry::extended()
+    } else {
+        Registry::standard()
+    };
+
+    // the trace and highlighting refer back to the original text, so those
+    // need the whole input; otherwise it can be streamed
+    let contents = (show_trace || show_highlight)
+        .then(|| fs::read_to_string(input_filename).expect("could not read file"));
+    let calls = match &contents {
+        Some(contents) => read_calls(contents.as_bytes(), registry.tokenizer()).unwrap(),
+        None => {
+            let file = File::open(input_filename).expect("could not open file");
+            read_calls(file, registry.tokenizer()).expect("could not read file")
+        }
+    };
+
+    if let Some(contents) = &contents {
+        let entries = trace(&registry, &calls, accumulate);
+        if show_trace {
+            print!("{}", format_trace(contents, &entries));
+            println!();
+        }
+        if show_highlight {
+            print!("{}", highlight(contents, &entries));
         }
     }
-    println!("sum of muls: {}", acc);
+
+    let totals = evaluate(&registry, &calls, accumulate);
+
+    println!("all instructions: {}", totals.unconditional);
+    println!("enabled instructions: {}", totals.gated);
+}
+
+/// Both answers from the same program: with every instruction counted
+/// (part 1), and with only those not switched off by `don't()` (part 2).
+#[derive(Debug, PartialEq)]
+struct Totals {
+    unconditional: i64,
+    gated: i64,
+}
+
+fn evaluate(registry: &Registry, calls: &[Spanned<Call>], accumulate: Accumulate) -> Totals {
+    let calls = || calls.iter().map(|call| &call.item);
+    Totals {
+        unconditional: Machine::new(accumulate, false).run(registry, calls()),
+        gated: Machine::new(accumulate, true).run(registry, calls()),
+    }
 }
 
-fn find_tokens(s: &str) -> Vec<Token> {
-    let regex = Regex::new(r"do\(\)|don't\(\)|mul\((\d{1,3}),(\d{1,3})\)").unwrap();
+/// The original regex tokenizer, kept as a reference for the streaming one.
+#[cfg(test)]
+fn find_tokens(s: &str) -> Vec<Spanned<Call>> {
+    let regex = Regex::new(r"do\(\)|don't\(\)|mul\(([0-9]{1,3}),([0-9]{1,3})\)").unwrap();
     regex
         .captures_iter(s)
-        .map(|captures| match &captures[0] {
-            "do()" => Token::Do,
-            "don't()" => Token::Dont,
-            _ => Token::Mul(captures[1].parse::<i64>().unwrap(), captures[2].parse::<i64>().unwrap())
+        .map(|captures| Spanned {
+            item: match &captures[0] {
+                "do()" => Call::new("do", vec![]),
+                "don't()" => Call::new("don't", vec![]),
+                _ => Call::new(
+                    "mul",
+                    vec![
+                        captures[1].parse::<i64>().unwrap(),
+                        captures[2].parse::<i64>().unwrap(),
+                    ],
+                ),
+            },
+            span: captures.get(0).unwrap().range(),
         })
         .collect()
 }
 
-#[derive(Debug)]
-enum Token {
-    Do,
-    Dont,
-    Mul(i64, i64),
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use super::{
+        evaluate, find_tokens,
+        tokenizer::{read_calls, Call, Spanned},
+        vm::{Accumulate, Instruction, Machine, Outcome, Registry},
+        Totals,
+    };
+    use crate::test_util::XorShift;
+
+    fn sum_muls(calls: Vec<Spanned<Call>>) -> Totals {
+        evaluate(&Registry::standard(), &calls, Accumulate::Sum)
+    }
+
+    fn read_standard(reader: impl Read) -> Vec<Spanned<Call>> {
+        read_calls(reader, Registry::standard().tokenizer()).unwrap()
+    }
+
+    fn items<T: Clone>(spanned: &[Spanned<T>]) -> Vec<T> {
+        spanned.iter().map(|s| s.item.clone()).collect()
+    }
+
+    /// Hands out at most `limit` bytes per read, like a slow pipe.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        limit: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.limit.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    /// Deterministic pseudo-random corrupted memory, built from fragments
+    /// of valid and broken instructions.
+    fn corrupted_memory(seed: u64, len: usize) -> String {
+        let fragments = [
+            "mul(",
+            "mul",
+            "(",
+            ")",
+            ",",
+            "do()",
+            "don't()",
+            "do",
+            "don't",
+            "'",
+            "m",
+            "u",
+            "l",
+            "d",
+            "o",
+            "n",
+            "t",
+            "1",
+            "23",
+            "456",
+            "7890",
+            " ",
+            "x",
+            "mul(12,3)",
+            "mmul(1,2)",
+        ];
+        let mut random = XorShift::new(seed);
+        (0..len).map(|_| *random.pick(&fragments)).collect()
+    }
+
+    #[test]
+    fn check_example_part1() {
+        let input = "xmul(2,4)%&mul[3,7]!@^do_not_mul(5,5)+mul(32,64]then(mul(11,8)mul(8,5))";
+        let sums = sum_muls(find_tokens(input));
+        assert_eq!(sums.unconditional, 161);
+        assert_eq!(sums.gated, 161);
+    }
+
+    #[test]
+    fn check_example_part2() {
+        let input = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
+        let sums = sum_muls(find_tokens(input));
+        assert_eq!(
+            sums,
+            Totals {
+                unconditional: 161,
+                gated: 48
+            }
+        );
+    }
+
+    #[test]
+    fn streaming_matches_regex() {
+        for seed in 1..200 {
+            let input = corrupted_memory(seed, 200);
+            let expected = find_tokens(&input);
+
+            for chunk_size in [1, 2, 3, 5, 8, 13, 64, input.len()] {
+                let mut tokenizer = Registry::standard().tokenizer();
+                let mut calls = vec![];
+                for chunk in input.as_bytes().chunks(chunk_size) {
+                    tokenizer.feed(chunk, &mut calls);
+                }
+                assert_eq!(calls, expected, "{:?} in chunks of {}", input, chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn read_calls_across_short_reads() {
+        let input = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
+        for limit in 1..8 {
+            let reader = Trickle {
+                data: input.as_bytes(),
+                limit,
+            };
+            assert_eq!(read_standard(reader), find_tokens(input));
+        }
+    }
+
+    #[test]
+    fn restarts_inside_broken_token() {
+        let input = "mmul(1,2)dmul(3,4)mul(1234,5)mul(6,7)dodon't()";
+        assert_eq!(read_standard(input.as_bytes()), find_tokens(input));
+        assert_eq!(
+            sum_muls(read_standard(input.as_bytes())),
+            Totals {
+                unconditional: 2 + 12 + 42,
+                gated: 2 + 12 + 42
+            }
+        );
+    }
+
+    #[test]
+    fn extended_instructions() {
+        let input = "mul(2,3)add(10,5)xsub(1,4)]don't()add(100,100)do()mul(1,1)";
+        let registry = Registry::extended();
+        let calls = registry.parse(input);
+        assert_eq!(
+            items(&calls),
+            vec![
+                Call::new("mul", vec![2, 3]),
+                Call::new("add", vec![10, 5]),
+                Call::new("sub", vec![1, 4]),
+                Call::new("don't", vec![]),
+                Call::new("add", vec![100, 100]),
+                Call::new("do", vec![]),
+                Call::new("mul", vec![1, 1]),
+            ]
+        );
+        assert_eq!(
+            evaluate(&registry, &calls, Accumulate::Sum),
+            Totals {
+                unconditional: 6 + 15 - 3 + 200 + 1,
+                gated: 6 + 15 - 3 + 1
+            }
+        );
+    }
+
+    #[test]
+    fn standard_registry_matches_tokenizer() {
+        let input = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?add(1,2)";
+        assert_eq!(Registry::standard().parse(input), find_tokens(input));
+    }
+
+    #[test]
+    fn nested_scopes() {
+        // the inner don't() only lasts until its scope ends
+        let input = "begin()mul(1,1)begin()don't()mul(2,2)end()mul(3,3)end()end()mul(4,4)";
+        let registry = Registry::extended();
+        let calls = registry.parse(input);
+        assert_eq!(
+            evaluate(&registry, &calls, Accumulate::Sum),
+            Totals {
+                unconditional: 1 + 4 + 9 + 16,
+                gated: 1 + 9 + 16
+            }
+        );
+    }
+
+    #[test]
+    fn accumulator_modes() {
+        let input = "mul(2,3)don't()mul(9,9)do()sub(1,5)";
+        let registry = Registry::extended();
+        let calls = registry.parse(input);
+        assert_eq!(
+            evaluate(&registry, &calls, Accumulate::Max),
+            Totals {
+                unconditional: 81,
+                gated: 6
+            }
+        );
+        assert_eq!(
+            evaluate(&registry, &calls, Accumulate::Count),
+            Totals {
+                unconditional: 3,
+                gated: 2
+            }
+        );
+    }
+
+    #[test]
+    fn custom_instruction() {
+        /// `neg(a)`: emits -a
+        struct Negate;
+
+        impl Instruction for Negate {
+            fn arity(&self) -> usize {
+                1
+            }
+
+            fn execute(&self, args: &[i64], machine: &mut Machine) -> Outcome {
+                machine.emit(-args[0])
+            }
+        }
+
+        let mut registry = Registry::standard();
+        registry.register("neg", Negate);
+        let calls = registry.parse("mul(3,4)neg(5)neg(1,2)neg()");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            Machine::new(Accumulate::Sum, true).run(&registry, items(&calls).iter()),
+            7
+        );
+    }
+}
+
+#[cfg(test)]
+mod properties {
+    use proptest::prelude::*;
+    use regex::Regex;
+
+    use super::{
+        find_tokens,
+        tokenizer::{read_calls, Call, Spanned},
+        vm::Registry,
+    };
+
+    /// A piece of generated corrupted memory.
+    #[derive(Debug, Clone)]
+    enum Segment {
+        /// Junk that cannot start an instruction, as it has no `m` or `d`
+        Noise(String),
+        /// A real instruction, which must be found
+        Valid(String, Call),
+        /// Looks like an instruction but is malformed, so must be skipped
+        NearValid(String),
+    }
+
+    fn valid() -> impl Strategy<Value = Segment> {
+        prop_oneof![
+            ("[0-9]{1,3}", "[0-9]{1,3}").prop_map(|(a, b)| {
+                let call = Call::new("mul", vec![a.parse().unwrap(), b.parse().unwrap()]);
+                Segment::Valid(format!("mul({},{})", a, b), call)
+            }),
+            Just(Segment::Valid("do()".to_string(), Call::new("do", vec![]))),
+            Just(Segment::Valid(
+                "don't()".to_string(),
+                Call::new("don't", vec![])
+            )),
+        ]
+    }
+
+    /// Each of these breaks on a byte that cannot begin another instruction,
+    /// so whatever follows is read from a clean slate.
+    fn near_valid() -> impl Strategy<Value = Segment> {
+        ("[0-9]{1,3}", "[0-9]{1,3}", 0..14).prop_map(|(a, b, kind)| {
+            Segment::NearValid(match kind {
+                0 => format!("mul( {},{})", a, b),
+                1 => format!("mul({} ,{})", a, b),
+                2 => format!("mul({},{} )", a, b),
+                3 => format!("mul[{},{}]", a, b),
+                4 => format!("mul({};{})", a, b),
+                5 => format!("mul({}0000,{})", a, b),
+                6 => format!("mul({},{}0000)", a, b),
+                7 => format!("mul(-{},{})", a, b),
+                8 => format!("mul(,{})", b),
+                9 => format!("mul({},)", a),
+                10 => format!("mul ({},{})", a, b),
+                11 => "do ()".to_string(),
+                12 => "don't ()".to_string(),
+                _ => "dont()".to_string(),
+            })
+        })
+    }
+
+    fn segment() -> impl Strategy<Value = Segment> {
+        prop_oneof![
+            "[^md]{0,12}".prop_map(Segment::Noise),
+            valid(),
+            near_valid(),
+        ]
+    }
+
+    fn assemble(segments: &[Segment]) -> (String, Vec<Call>) {
+        let mut input = String::new();
+        let mut planted = vec![];
+        for segment in segments {
+            match segment {
+                Segment::Noise(s) | Segment::NearValid(s) => input.push_str(s),
+                Segment::Valid(s, call) => {
+                    input.push_str(s);
+                    planted.push(call.clone());
+                }
+            }
+        }
+        (input, planted)
+    }
+
+    fn items(calls: Vec<Spanned<Call>>) -> Vec<Call> {
+        calls.into_iter().map(|call| call.item).collect()
+    }
+
+    fn read_standard(input: &str) -> Vec<Spanned<Call>> {
+        read_calls(input.as_bytes(), Registry::standard().tokenizer()).unwrap()
+    }
+
+    /// The regex equivalent of the extended instruction set, as a reference
+    /// for the tokenizer built from its registry.
+    fn find_extended(s: &str) -> Vec<Spanned<Call>> {
+        let regex =
+            Regex::new(r"(mul|add|sub)\(([0-9]{1,3}),([0-9]{1,3})\)|(do|don't|begin|end)\(\)")
+                .unwrap();
+        regex
+            .captures_iter(s)
+            .map(|captures| Spanned {
+                item: match captures.get(1) {
+                    Some(opcode) => Call::new(
+                        opcode.as_str(),
+                        vec![captures[2].parse().unwrap(), captures[3].parse().unwrap()],
+                    ),
+                    None => Call::new(&captures[4], vec![]),
+                },
+                span: captures.get(0).unwrap().range(),
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn finds_exactly_the_planted_instructions(
+            segments in prop::collection::vec(segment(), 0..40)
+        ) {
+            let (input, planted) = assemble(&segments);
+            prop_assert_eq!(items(find_tokens(&input)), planted.clone());
+            prop_assert_eq!(items(read_standard(&input)), planted);
+        }
+
+        #[test]
+        fn spans_cover_the_instruction_text(
+            segments in prop::collection::vec(segment(), 0..40)
+        ) {
+            let (input, _) = assemble(&segments);
+            for call in read_standard(&input) {
+                let text = &input[call.span.clone()];
+                prop_assert_eq!(items(find_tokens(text)), vec![call.item]);
+            }
+        }
+
+        #[test]
+        fn streaming_matches_regex_in_any_chunks(
+            input in "[mul(),0-9don't \\[\\]x]{0,120}",
+            chunk_size in 1usize..16
+        ) {
+            let mut tokenizer = Registry::standard().tokenizer();
+            let mut calls = vec![];
+            for chunk in input.as_bytes().chunks(chunk_size) {
+                tokenizer.feed(chunk, &mut calls);
+            }
+            prop_assert_eq!(calls, find_tokens(&input));
+        }
+
+        #[test]
+        fn extended_streaming_matches_regex_in_any_chunks(
+            input in "[mulasdbegindon't(),0-9 x]{0,120}",
+            chunk_size in 1usize..16
+        ) {
+            let mut tokenizer = Registry::extended().tokenizer();
+            let mut calls = vec![];
+            for chunk in input.as_bytes().chunks(chunk_size) {
+                tokenizer.feed(chunk, &mut calls);
+            }
+            prop_assert_eq!(calls, find_extended(&input));
+        }
+    }
 }
@@ -0,0 +1,195 @@
+use std::{
+    io::{self, ErrorKind, Read},
+    ops::Range,
+};
+
+/// How much input to read from the source at a time.
+const CHUNK_SIZE: usize = 8192;
+
+/// A call to a named instruction, e.g. `mul(2,4)` or `do()`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Call {
+    pub(crate) opcode: String,
+    pub(crate) args: Vec<i64>,
+}
+
+impl Call {
+    pub(crate) fn new(opcode: &str, args: Vec<i64>) -> Self {
+        Self {
+            opcode: opcode.to_string(),
+            args,
+        }
+    }
+}
+
+/// Something recognised in the input, with the byte range it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Spanned<T> {
+    pub(crate) item: T,
+    pub(crate) span: Range<usize>,
+}
+
+/// Reads `Call`s from any source without holding the whole input in memory.
+pub(crate) fn read_calls<R: Read>(
+    mut reader: R,
+    mut tokenizer: Tokenizer,
+) -> io::Result<Vec<Spanned<Call>>> {
+    let mut calls = vec![];
+    let mut buf = [0u8; CHUNK_SIZE];
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        tokenizer.feed(&buf[..n], &mut calls);
+    }
+
+    Ok(calls)
+}
+
+/// Recognises calls to a given set of opcodes: the opcode, then exactly as
+/// many 1-3 digit arguments as it takes in parentheses, separated by commas
+/// with no whitespace.
+///
+/// Input can be fed in chunks of any size. Only the bytes which might still
+/// begin a call are held back between chunks, so the memory needed is bounded
+/// by the longest possible call rather than the size of the input.
+pub(crate) struct Tokenizer {
+    /// Each opcode with how many arguments it takes
+    opcodes: Vec<(String, usize)>,
+    /// Bytes fed but not yet decided on
+    pending: Vec<u8>,
+    /// Offset of the first pending byte
+    offset: usize,
+}
+
+/// Why a call doesn't match at some position.
+enum Mismatch {
+    /// The input ran out first, so more of it might still complete the call
+    Incomplete,
+    /// Nothing that follows can make it match
+    Never,
+}
+
+impl Tokenizer {
+    /// Opcodes may not contain parentheses, so a call's closing `)` is always
+    /// its last byte.
+    pub(crate) fn new<'a>(opcodes: impl IntoIterator<Item = (&'a str, usize)>) -> Self {
+        let opcodes = opcodes
+            .into_iter()
+            .map(|(opcode, arity)| {
+                assert!(
+                    !opcode.contains(['(', ')']),
+                    "opcode {:?} can't contain parentheses",
+                    opcode
+                );
+                (opcode.to_string(), arity)
+            })
+            .collect();
+        Self {
+            opcodes,
+            pending: vec![],
+            offset: 0,
+        }
+    }
+
+    /// Calls are found leftmost first, and from the same position the longest
+    /// wins. Nothing is left to flush at the end of the input: a call which is
+    /// still incomplete has no `)` yet, so no call can be hiding inside it.
+    pub(crate) fn feed(&mut self, chunk: &[u8], calls: &mut Vec<Spanned<Call>>) {
+        self.pending.extend_from_slice(chunk);
+
+        let mut pos = 0;
+        while pos < self.pending.len() {
+            let rest = &self.pending[pos..];
+            let mut longest: Option<(Call, usize)> = None;
+            let mut incomplete = false;
+            for (opcode, arity) in &self.opcodes {
+                match match_call(rest, opcode.as_bytes(), *arity) {
+                    Ok((args, len)) => {
+                        if longest.as_ref().is_none_or(|&(_, longest)| len > longest) {
+                            longest = Some((Call::new(opcode, args), len));
+                        }
+                    }
+                    Err(Mismatch::Incomplete) => incomplete = true,
+                    Err(Mismatch::Never) => {}
+                }
+            }
+
+            if incomplete {
+                break;
+            }
+            if let Some((call, len)) = longest {
+                let start = self.offset + pos;
+                calls.push(Spanned {
+                    item: call,
+                    span: start..start + len,
+                });
+                pos += len;
+            } else {
+                pos += 1;
+            }
+        }
+
+        self.pending.drain(..pos);
+        self.offset += pos;
+    }
+}
+
+/// Matches `opcode(a,b,...)` at the start of `bytes`, returning the
+/// arguments and the length of the match.
+fn match_call(bytes: &[u8], opcode: &[u8], arity: usize) -> Result<(Vec<i64>, usize), Mismatch> {
+    if bytes.len() < opcode.len() {
+        return Err(if opcode.starts_with(bytes) {
+            Mismatch::Incomplete
+        } else {
+            Mismatch::Never
+        });
+    }
+    if !bytes.starts_with(opcode) {
+        return Err(Mismatch::Never);
+    }
+    let mut pos = opcode.len();
+    expect(bytes, pos, b'(')?;
+    pos += 1;
+
+    let mut args = Vec::with_capacity(arity);
+    for i in 0..arity {
+        if i > 0 {
+            expect(bytes, pos, b',')?;
+            pos += 1;
+        }
+
+        let mut value = 0;
+        let mut digits = 0;
+        while let Some(&byte) = bytes.get(pos).filter(|byte| byte.is_ascii_digit()) {
+            if digits == 3 {
+                return Err(Mismatch::Never);
+            }
+            value = value * 10 + (byte - b'0') as i64;
+            digits += 1;
+            pos += 1;
+        }
+        if pos == bytes.len() {
+            return Err(Mismatch::Incomplete);
+        }
+        if digits == 0 {
+            return Err(Mismatch::Never);
+        }
+        args.push(value);
+    }
+
+    expect(bytes, pos, b')')?;
+    Ok((args, pos + 1))
+}
+
+fn expect(bytes: &[u8], pos: usize, byte: u8) -> Result<(), Mismatch> {
+    match bytes.get(pos) {
+        None => Err(Mismatch::Incomplete),
+        Some(&found) if found == byte => Ok(()),
+        Some(_) => Err(Mismatch::Never),
+    }
+}
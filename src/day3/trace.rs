@@ -0,0 +1,205 @@
+use std::{fmt::Write, ops::Range};
+
+use super::{
+    tokenizer::{Call, Spanned},
+    vm::{Accumulate, Machine, Outcome, Registry},
+};
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// One recognised instruction and what happened when it ran.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TraceEntry {
+    pub(crate) span: Range<usize>,
+    pub(crate) outcome: Outcome,
+    /// The accumulator after this instruction
+    pub(crate) acc: i64,
+}
+
+/// Runs the program with `do()`/`don't()` gating, recording every step.
+pub(crate) fn trace(
+    registry: &Registry,
+    calls: &[Spanned<Call>],
+    accumulate: Accumulate,
+) -> Vec<TraceEntry> {
+    let mut machine = Machine::new(accumulate, true);
+    calls
+        .iter()
+        .map(|call| {
+            let outcome = machine.step(registry, &call.item);
+            TraceEntry {
+                span: call.span.clone(),
+                outcome,
+                acc: machine.value(),
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn format_trace(input: &str, entries: &[TraceEntry]) -> String {
+    let lines = LineIndex::new(input);
+    let mut out = format!(
+        "{:>8} {:<10} {:<20} {:<10} {}\n",
+        "offset", "line:col", "instruction", "decision", "acc"
+    );
+
+    for entry in entries {
+        let (line, col) = lines.line_col(input, entry.span.start);
+        let decision = match entry.outcome {
+            Outcome::Applied(value) => format!("+{}", value),
+            Outcome::Suppressed(_) => "suppressed".to_string(),
+            Outcome::Control => "-".to_string(),
+            Outcome::Unknown => "unknown".to_string(),
+        };
+        writeln!(
+            out,
+            "{:>8} {:<10} {:<20} {:<10} {}",
+            entry.span.start,
+            format!("{}:{}", line, col),
+            &input[entry.span.clone()],
+            decision,
+            entry.acc
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+/// The input with applied instructions in green, suppressed ones in red and
+/// control instructions in yellow.
+pub(crate) fn highlight(input: &str, entries: &[TraceEntry]) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut pos = 0;
+
+    for entry in entries {
+        let colour = match entry.outcome {
+            Outcome::Applied(_) => GREEN,
+            Outcome::Suppressed(_) => RED,
+            Outcome::Control => YELLOW,
+            Outcome::Unknown => continue,
+        };
+        out.push_str(&input[pos..entry.span.start]);
+        out.push_str(colour);
+        out.push_str(&input[entry.span.clone()]);
+        out.push_str(RESET);
+        pos = entry.span.end;
+    }
+    out.push_str(&input[pos..]);
+
+    out
+}
+
+/// Maps byte offsets to 1-based line and column numbers.
+struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(input: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { line_starts }
+    }
+
+    fn line_col(&self, input: &str, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let col = input[self.line_starts[line]..offset].chars().count();
+        (line + 1, col + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_trace, highlight, trace, LineIndex, TraceEntry, GREEN, RED, RESET, YELLOW};
+    use crate::day3::{
+        tokenizer::read_calls,
+        vm::{Accumulate, Outcome, Registry},
+    };
+
+    fn example() -> (&'static str, Vec<TraceEntry>) {
+        let input = "xmul(2,4)&don't()_mul(5,5)\n+do()mul(8,5))";
+        let registry = Registry::standard();
+        let calls = read_calls(input.as_bytes(), registry.tokenizer()).unwrap();
+        (input, trace(&registry, &calls, Accumulate::Sum))
+    }
+
+    #[test]
+    fn trace_decisions() {
+        let (_, entries) = example();
+        assert_eq!(
+            entries,
+            vec![
+                TraceEntry {
+                    span: 1..9,
+                    outcome: Outcome::Applied(8),
+                    acc: 8
+                },
+                TraceEntry {
+                    span: 10..17,
+                    outcome: Outcome::Control,
+                    acc: 8
+                },
+                TraceEntry {
+                    span: 18..26,
+                    outcome: Outcome::Suppressed(25),
+                    acc: 8
+                },
+                TraceEntry {
+                    span: 28..32,
+                    outcome: Outcome::Control,
+                    acc: 8
+                },
+                TraceEntry {
+                    span: 32..40,
+                    outcome: Outcome::Applied(40),
+                    acc: 48
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_table() {
+        let (input, entries) = example();
+        let table = format_trace(input, &entries);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[3],
+            "      18 1:19       mul(5,5)             suppressed 8"
+        );
+        assert_eq!(
+            lines[5],
+            "      32 2:6        mul(8,5)             +40        48"
+        );
+    }
+
+    #[test]
+    fn highlighted_input() {
+        let (input, entries) = example();
+        let expected = format!(
+            "x{g}mul(2,4){r}&{y}don't(){r}_{red}mul(5,5){r}\n+{y}do(){r}{g}mul(8,5){r})",
+            g = GREEN,
+            y = YELLOW,
+            red = RED,
+            r = RESET
+        );
+        assert_eq!(highlight(input, &entries), expected);
+    }
+
+    #[test]
+    fn line_and_column() {
+        let input = "ab\ncdé\n\nf";
+        let lines = LineIndex::new(input);
+        assert_eq!(lines.line_col(input, 0), (1, 1));
+        assert_eq!(lines.line_col(input, 3), (2, 1));
+        assert_eq!(lines.line_col(input, 7), (2, 4));
+        assert_eq!(lines.line_col(input, 8), (3, 1));
+        assert_eq!(lines.line_col(input, 9), (4, 1));
+    }
+}
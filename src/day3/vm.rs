@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+
+#[cfg(test)]
+use super::tokenizer::{read_calls, Spanned};
+use super::tokenizer::{Call, Tokenizer};
+
+/// What a single instruction did when it ran.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Outcome {
+    /// Produced a value, which went into the accumulator
+    Applied(i64),
+    /// Produced a value, but its scope was disabled
+    Suppressed(i64),
+    /// Changed the machine's state without producing a value
+    Control,
+    /// Not a registered instruction, or the wrong number of arguments
+    Unknown,
+}
+
+pub(crate) trait Instruction {
+    /// How many arguments a call must have to be recognised
+    fn arity(&self) -> usize;
+
+    fn execute(&self, args: &[i64], machine: &mut Machine) -> Outcome;
+}
+
+/// Computes a value from its arguments and hands it to the accumulator.
+struct Arithmetic(fn(i64, i64) -> i64);
+
+impl Instruction for Arithmetic {
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn execute(&self, args: &[i64], machine: &mut Machine) -> Outcome {
+        machine.emit((self.0)(args[0], args[1]))
+    }
+}
+
+/// `do()` and `don't()`: switch the current scope on or off.
+struct SetEnabled(bool);
+
+impl Instruction for SetEnabled {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn execute(&self, _args: &[i64], machine: &mut Machine) -> Outcome {
+        machine.set_enabled(self.0);
+        Outcome::Control
+    }
+}
+
+/// Opens a nested scope, which starts out in the same state as its parent.
+struct BeginScope;
+
+impl Instruction for BeginScope {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn execute(&self, _args: &[i64], machine: &mut Machine) -> Outcome {
+        machine.begin_scope();
+        Outcome::Control
+    }
+}
+
+/// Closes the innermost scope, restoring whatever its parent was set to.
+struct EndScope;
+
+impl Instruction for EndScope {
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn execute(&self, _args: &[i64], machine: &mut Machine) -> Outcome {
+        machine.end_scope();
+        Outcome::Control
+    }
+}
+
+/// The instructions a program may contain, by opcode.
+pub(crate) struct Registry {
+    instructions: BTreeMap<String, Box<dyn Instruction>>,
+}
+
+impl Registry {
+    pub(crate) fn new() -> Self {
+        Self {
+            instructions: BTreeMap::new(),
+        }
+    }
+
+    /// `mul`, `do` and `don't`, as in the puzzle.
+    pub(crate) fn standard() -> Self {
+        let mut registry = Self::new();
+        registry.register("mul", Arithmetic(|a, b| a * b));
+        registry.register("do", SetEnabled(true));
+        registry.register("don't", SetEnabled(false));
+        registry
+    }
+
+    /// The standard set plus `add`, `sub` and `begin`/`end` scopes.
+    pub(crate) fn extended() -> Self {
+        let mut registry = Self::standard();
+        registry.register("add", Arithmetic(|a, b| a + b));
+        registry.register("sub", Arithmetic(|a, b| a - b));
+        registry.register("begin", BeginScope);
+        registry.register("end", EndScope);
+        registry
+    }
+
+    pub(crate) fn register(&mut self, opcode: &str, instruction: impl Instruction + 'static) {
+        self.instructions
+            .insert(opcode.to_string(), Box::new(instruction));
+    }
+
+    /// A tokenizer for calls to the registered instructions.
+    pub(crate) fn tokenizer(&self) -> Tokenizer {
+        Tokenizer::new(
+            self.instructions
+                .iter()
+                .map(|(opcode, instruction)| (opcode.as_str(), instruction.arity())),
+        )
+    }
+
+    #[cfg(test)]
+    pub(crate) fn parse(&self, input: &str) -> Vec<Spanned<Call>> {
+        read_calls(input.as_bytes(), self.tokenizer()).unwrap()
+    }
+
+    fn execute(&self, call: &Call, machine: &mut Machine) -> Outcome {
+        match self.instructions.get(&call.opcode) {
+            Some(instruction) if instruction.arity() == call.args.len() => {
+                instruction.execute(&call.args, machine)
+            }
+            _ => Outcome::Unknown,
+        }
+    }
+}
+
+/// How the values produced by instructions are combined.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum Accumulate {
+    /// Add them all up, as in the puzzle
+    Sum,
+    /// Keep the largest
+    Max,
+    /// Count how many there were
+    Count,
+}
+
+pub(crate) struct Machine {
+    /// Enabled state of each open scope; the last one is current
+    scopes: Vec<bool>,
+    /// Whether disabled scopes suppress values, or everything counts
+    gated: bool,
+    accumulate: Accumulate,
+    acc: Option<i64>,
+}
+
+impl Machine {
+    pub(crate) fn new(accumulate: Accumulate, gated: bool) -> Self {
+        Self {
+            scopes: vec![true],
+            gated,
+            accumulate,
+            acc: None,
+        }
+    }
+
+    pub(crate) fn run<'a>(
+        &mut self,
+        registry: &Registry,
+        calls: impl IntoIterator<Item = &'a Call>,
+    ) -> i64 {
+        for call in calls {
+            self.step(registry, call);
+        }
+        self.value()
+    }
+
+    pub(crate) fn step(&mut self, registry: &Registry, call: &Call) -> Outcome {
+        registry.execute(call, self)
+    }
+
+    pub(crate) fn value(&self) -> i64 {
+        self.acc.unwrap_or(0)
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        *self.scopes.last().unwrap()
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        *self.scopes.last_mut().unwrap() = enabled;
+    }
+
+    pub(crate) fn begin_scope(&mut self) {
+        self.scopes.push(self.is_enabled());
+    }
+
+    /// Unbalanced `end`s are ignored rather than closing the outermost scope.
+    pub(crate) fn end_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    pub(crate) fn emit(&mut self, value: i64) -> Outcome {
+        if self.gated && !self.is_enabled() {
+            return Outcome::Suppressed(value);
+        }
+
+        self.acc = Some(match (self.accumulate, self.acc) {
+            (Accumulate::Sum, acc) => acc.unwrap_or(0) + value,
+            (Accumulate::Max, Some(acc)) => acc.max(value),
+            (Accumulate::Max, None) => value,
+            (Accumulate::Count, acc) => acc.unwrap_or(0) + 1,
+        });
+        Outcome::Applied(value)
+    }
+}
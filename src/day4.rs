@@ -1,63 +1,434 @@
+use aho_corasick::AhoCorasick;
+use clap::ValueEnum;
 use grid::Grid;
 use std::fs;
 
-pub(crate) fn day4(input_filename: &str) {
+pub(crate) fn day4(
+    input_filename: &str,
+    words: &[String],
+    patterns: &[String],
+    list_matches: bool,
+    show: Option<Render>,
+) {
     let contents = fs::read_to_string(input_filename).expect("could not read file");
-    let count = count_xmas(contents);
-    println!("count: {}", count);
+    let grid = parse_grid(&contents);
+    let words = words.iter().map(|word| word.as_str()).collect::<Vec<_>>();
+    let matches = find_words(&grid, &words);
+
+    if list_matches {
+        for found in &matches {
+            println!(
+                "{} at row {}, col {} reading {}",
+                words[found.word],
+                found.start.0,
+                found.start.1,
+                direction_name(found.direction)
+            );
+        }
+        println!();
+    }
+    if let Some(style) = show {
+        println!("{}", render(&grid, &words, &matches, style));
+    }
+
+    for (word, count) in words.iter().zip(count_words(&matches, words.len())) {
+        println!("count of {}: {}", word, count);
+    }
+    for pattern in patterns {
+        let count = count_pattern(&grid, &Pattern::parse(pattern));
+        println!("count of {}: {}", pattern, count);
+    }
 }
 
-fn count_xmas(input: String) -> usize {
+fn parse_grid(input: &str) -> Grid<char> {
     let lines = input.lines().collect::<Vec<_>>();
-    let size_x = lines.first().expect("empty!").len();
-    let size_y = lines.len();
-    let mut grid = Grid::<char>::new(size_x, size_y);
-    for y in 0..size_y {
-        grid.insert_row(y, lines[y].chars().collect());
-    }
-
-    // Table of offsets from the current position in the grid
-    // TODO: Generalise to get_offsets(k: usize)
-    let searches = vec![
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], ( 0,  1)),  // 0 +
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], ( 1,  1)),  // + +
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], ( 1,  0)),  // + 0
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], (-1,  1)),  // - +
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], ( 0, -1)),  // 0 -
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], (-1, -1)),  // - -
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], (-1,  0)),  // - 0
-        mul(vec![(0, 0), (1, 1), (2, 2), (3, 3)], ( 1, -1)),  // + -
-    ];
+    let cols = lines.first().expect("empty!").chars().count();
+    Grid::from_vec(lines.iter().flat_map(|line| line.chars()).collect(), cols)
+}
 
-    let sum: usize = grid
-        .indexed_iter()
-        .map(|((x, y), _)| {
-            searches
-                .iter()
-                // build all possible strings from the current grid position
-                .map(|search| {
-                    search
-                        .iter()
-                        .map(|&(j, k)| grid.get(x as i64 + j, y as i64 + k))
-                        .filter(|ch| ch.is_some())
-                        .map(|ch| *ch.unwrap())
-                        .collect::<String>()
-                })
-                // then count the ones that say XMAS
-                .filter(|s| s == "XMAS")
-                .count()
+/// Counts the placements of each of `num_words` words among those found by
+/// `find_words`.
+fn count_words(matches: &[WordMatch], num_words: usize) -> Vec<usize> {
+    let mut counts = vec![0; num_words];
+    for found in matches {
+        counts[found.word] += 1;
+    }
+    counts
+}
+
+/// One placement of a word in the grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WordMatch {
+    /// (row, col) of the word's first letter
+    start: (usize, usize),
+    /// (row, col) step from each letter to the next
+    direction: (i64, i64),
+    /// Index of the word in the list searched for
+    word: usize,
+}
+
+impl WordMatch {
+    fn cells(&self, len: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..len as i64).map(|i| {
+            (
+                (self.start.0 as i64 + i * self.direction.0) as usize,
+                (self.start.1 as i64 + i * self.direction.1) as usize,
+            )
+        })
+    }
+}
+
+/// Finds every placement of each word in the grid, reading in any
+/// direction, with a single pass over every row, column and diagonal.
+///
+/// A placement is the set of cells a word covers, so words which read the
+/// same both ways are only found once per line rather than once in each
+/// direction, and a single letter is found once per cell.
+fn find_words(grid: &Grid<char>, words: &[&str]) -> Vec<WordMatch> {
+    // Each spelling to look for, and the words it is a reading of (forwards
+    // or backwards). A word read backwards is found by also looking for its
+    // reverse, so that each line only needs scanning one way.
+    let mut spellings: Vec<String> = vec![];
+    let mut readings: Vec<Vec<(usize, bool)>> = vec![];
+    for (i, word) in words.iter().enumerate() {
+        let reversed = word.chars().rev().collect::<String>();
+        for (spelling, backwards) in [(word.to_string(), false), (reversed, true)] {
+            if spelling.is_empty() {
+                continue;
+            }
+            let idx = match spellings.iter().position(|s| *s == spelling) {
+                Some(idx) => idx,
+                None => {
+                    spellings.push(spelling);
+                    readings.push(vec![]);
+                    spellings.len() - 1
+                }
+            };
+            // palindromes are their own reverse: only find them once
+            if !readings[idx].iter().any(|&(word, _)| word == i) {
+                readings[idx].push((i, backwards));
+            }
+        }
+    }
+
+    let mut matches = vec![];
+    if spellings.is_empty() {
+        return matches;
+    }
+
+    let lengths = spellings
+        .iter()
+        .map(|s| s.chars().count())
+        .collect::<Vec<_>>();
+    let automaton = AhoCorasick::new(&spellings).unwrap();
+
+    for line in grid_lines(grid) {
+        for found in automaton.find_overlapping_iter(&line.text) {
+            let spelling = found.pattern().as_usize();
+            // every line through a cell contains it; only count it on its row
+            if lengths[spelling] == 1 && line.step != ROW {
+                continue;
+            }
+
+            let first = line.char_index(found.start());
+            let last = first + lengths[spelling] - 1;
+            for &(word, backwards) in &readings[spelling] {
+                matches.push(if backwards {
+                    WordMatch {
+                        start: line.cell(last),
+                        direction: (-line.step.0, -line.step.1),
+                        word,
+                    }
+                } else {
+                    WordMatch {
+                        start: line.cell(first),
+                        direction: line.step,
+                        word,
+                    }
+                });
+            }
+        }
+    }
+
+    matches
+}
+
+const ROW: (i64, i64) = (0, 1);
+const COLUMN: (i64, i64) = (1, 0);
+const DIAGONAL: (i64, i64) = (1, 1);
+const ANTI_DIAGONAL: (i64, i64) = (1, -1);
+
+/// A straight run of cells across the grid, read as a string.
+struct Line {
+    text: String,
+    /// Byte offset in `text` of each cell's letter
+    char_starts: Vec<usize>,
+    /// (row, col) of the first cell
+    start: (usize, usize),
+    /// The (row, col) step from each cell to the next
+    step: (i64, i64),
+}
+
+impl Line {
+    fn char_index(&self, byte_offset: usize) -> usize {
+        self.char_starts.binary_search(&byte_offset).unwrap()
+    }
+
+    fn cell(&self, i: usize) -> (usize, usize) {
+        (
+            (self.start.0 as i64 + i as i64 * self.step.0) as usize,
+            (self.start.1 as i64 + i as i64 * self.step.1) as usize,
+        )
+    }
+}
+
+/// Every row, column and diagonal of the grid.
+fn grid_lines(grid: &Grid<char>) -> Vec<Line> {
+    let (rows, cols) = grid.size();
+    let last_col = cols.saturating_sub(1);
+
+    let starts = (0..rows)
+        .map(|row| ((row, 0), ROW))
+        .chain((0..cols).map(|col| ((0, col), COLUMN)))
+        .chain((0..cols).map(|col| ((0, col), DIAGONAL)))
+        .chain((1..rows).map(|row| ((row, 0), DIAGONAL)))
+        .chain((0..cols).map(|col| ((0, col), ANTI_DIAGONAL)))
+        .chain((1..rows).map(|row| ((row, last_col), ANTI_DIAGONAL)));
+
+    starts
+        .map(|((row, col), step)| {
+            let text = (0..)
+                .map_while(|i| grid.get(row as i64 + i * step.0, col as i64 + i * step.1))
+                .collect::<String>();
+            let char_starts = text.char_indices().map(|(i, _)| i).collect();
+            Line {
+                text,
+                char_starts,
+                start: (row, col),
+                step,
+            }
         })
-        .sum();
+        .collect()
+}
+
+/// How to show which cells of the grid are part of a match.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum Render {
+    /// Replace letters which are not part of any match with `.`
+    Plain,
+    /// Show matched letters in green and dim the rest
+    Colour,
+}
+
+fn render(grid: &Grid<char>, words: &[&str], matches: &[WordMatch], style: Render) -> String {
+    let mut matched = Grid::new(grid.rows(), grid.cols());
+    for found in matches {
+        for (row, col) in found.cells(words[found.word].chars().count()) {
+            matched[(row, col)] = true;
+        }
+    }
+
+    let mut out = String::new();
+    for (row, cells) in grid.iter_rows().enumerate() {
+        for (col, &ch) in cells.enumerate() {
+            match (style, matched[(row, col)]) {
+                (Render::Plain, true) => out.push(ch),
+                (Render::Plain, false) => out.push('.'),
+                (Render::Colour, true) => out.push_str(&format!("{}{}{}", GREEN, ch, RESET)),
+                (Render::Colour, false) => out.push_str(&format!("{}{}{}", DIM, ch, RESET)),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+const GREEN: &str = "\x1b[1;32m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+fn direction_name(direction: (i64, i64)) -> &'static str {
+    match direction {
+        (0, 1) => "right",
+        (0, -1) => "left",
+        (1, 0) => "down",
+        (-1, 0) => "up",
+        (1, 1) => "down-right",
+        (-1, -1) => "up-left",
+        (1, -1) => "down-left",
+        (-1, 1) => "up-right",
+        _ => unreachable!("not a grid direction: {:?}", direction),
+    }
+}
+
+/// A small 2D template of letters, where `.` matches any letter.
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern(Vec<Vec<Option<char>>>);
+
+impl Pattern {
+    /// Rows are separated by `/` or newlines, e.g. `M.S/.A./M.S`.
+    fn parse(s: &str) -> Self {
+        let rows = s
+            .split(['/', '\n'])
+            .filter(|row| !row.is_empty())
+            .map(|row| {
+                row.chars()
+                    .map(|ch| if ch == '.' { None } else { Some(ch) })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(!rows.is_empty(), "empty pattern!");
+        assert!(
+            rows.iter().all(|row| row.len() == rows[0].len()),
+            "pattern rows must all be the same length: {}",
+            s
+        );
+        Pattern(rows)
+    }
 
-    sum
+    fn rows(&self) -> usize {
+        self.0.len()
+    }
+
+    fn cols(&self) -> usize {
+        self.0[0].len()
+    }
+
+    /// Quarter turn clockwise.
+    fn rotate(&self) -> Self {
+        Pattern(
+            (0..self.cols())
+                .map(|col| (0..self.rows()).rev().map(|row| self.0[row][col]).collect())
+                .collect(),
+        )
+    }
+
+    /// Mirror image, flipped left to right.
+    fn reflect(&self) -> Self {
+        Pattern(
+            self.0
+                .iter()
+                .map(|row| row.iter().rev().copied().collect())
+                .collect(),
+        )
+    }
+
+    /// Every distinct rotation and reflection of the pattern. Symmetric
+    /// patterns have fewer than eight, and each is only listed once.
+    fn orientations(&self) -> Vec<Pattern> {
+        let mut orientations: Vec<Pattern> = vec![];
+        let mut pattern = self.clone();
+        for _ in 0..4 {
+            for candidate in [pattern.reflect(), pattern.clone()] {
+                if !orientations.contains(&candidate) {
+                    orientations.push(candidate);
+                }
+            }
+            pattern = pattern.rotate();
+        }
+        orientations
+    }
+
+    fn matches_at(&self, grid: &Grid<char>, row: usize, col: usize) -> bool {
+        // wildcards still have to land inside the grid
+        if row + self.rows() > grid.rows() || col + self.cols() > grid.cols() {
+            return false;
+        }
+
+        self.0.iter().enumerate().all(|(j, pattern_row)| {
+            pattern_row.iter().enumerate().all(|(k, cell)| match cell {
+                None => true,
+                Some(letter) => grid.get(row + j, col + k) == Some(letter),
+            })
+        })
+    }
 }
 
-fn mul(vec: Vec<(i64, i64)>, (j, k): (i64, i64)) -> Vec<(i64, i64)> {
-    vec.iter().map(|&(x, y)| (x * j, y * k)).collect()
+/// Counts the places the pattern appears in the grid in any orientation.
+fn count_pattern(grid: &Grid<char>, pattern: &Pattern) -> usize {
+    pattern
+        .orientations()
+        .iter()
+        .map(|pattern| {
+            grid.indexed_iter()
+                .filter(|&((row, col), _)| pattern.matches_at(grid, row, col))
+                .count()
+        })
+        .sum()
 }
 
+#[cfg(test)]
 mod tests {
-    use super::count_xmas;
+    use std::time::Instant;
+
+    use grid::Grid;
+
+    use super::{
+        count_pattern, count_words, find_words, parse_grid, render, Pattern, Render, WordMatch,
+    };
+    use crate::test_util::XorShift;
+
+    fn count_word(grid: &Grid<char>, word: &str) -> usize {
+        count_words(&find_words(grid, &[word]), 1)[0]
+    }
+
+    /// The eight directions a word can be read in, as (row, col) steps. Each
+    /// direction is followed by its opposite.
+    const DIRECTIONS: [(i64, i64); 8] = [
+        (0, 1),   // 0 +
+        (0, -1),  // 0 -
+        (1, 0),   // + 0
+        (-1, 0),  // - 0
+        (1, 1),   // + +
+        (-1, -1), // - -
+        (1, -1),  // + -
+        (-1, 1),  // - +
+    ];
+
+    /// The original search, which checks every direction from every cell.
+    fn count_word_by_offsets(grid: &Grid<char>, word: &str) -> usize {
+        let letters = word.chars().collect::<Vec<_>>();
+        let searches = get_offsets(&letters);
+
+        grid.indexed_iter()
+            .map(|((row, col), _)| {
+                searches
+                    .iter()
+                    .filter(|search| {
+                        search.iter().zip(&letters).all(|(&(j, k), letter)| {
+                            grid.get(row as i64 + j, col as i64 + k) == Some(letter)
+                        })
+                    })
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Table of offsets from the current position in the grid to each letter of
+    /// `letters`, one row per direction worth searching.
+    fn get_offsets(letters: &[char]) -> Vec<Vec<(i64, i64)>> {
+        let is_palindrome = letters.iter().eq(letters.iter().rev());
+        let directions = match letters.len() {
+            0 => vec![],
+            // every direction covers the same single cell
+            1 => vec![DIRECTIONS[0]],
+            // reading the other way would find the same cells again
+            _ if is_palindrome => DIRECTIONS.iter().step_by(2).copied().collect(),
+            _ => DIRECTIONS.to_vec(),
+        };
+
+        directions
+            .iter()
+            .map(|&(j, k)| (0..letters.len() as i64).map(|i| (i * j, i * k)).collect())
+            .collect()
+    }
+
+    /// A pseudo-random grid drawn from `letters`.
+    fn random_grid(seed: u64, rows: usize, cols: usize, letters: &[char]) -> Grid<char> {
+        let mut random = XorShift::new(seed);
+        let cells = (0..rows * cols).map(|_| *random.pick(letters)).collect();
+        Grid::from_vec(cells, cols)
+    }
 
     #[test]
     fn check_example() {
@@ -71,8 +442,190 @@ SMSMSASXSS
 SAXAMASAAA
 MAMMMXMMMM
 MXMXAXMASX
-"
-        .to_string();
-        assert_eq!(count_xmas(input), 18);
+";
+        let grid = parse_grid(input);
+        assert_eq!(count_word(&grid, "XMAS"), 18);
+        assert_eq!(count_word(&grid, "SAMX"), 18);
+    }
+
+    #[test]
+    fn non_square_grid() {
+        let input = "XMASAMX
+.......
+";
+        let grid = parse_grid(input);
+        assert_eq!(count_word(&grid, "XMAS"), 2);
+        assert_eq!(count_word(&grid, "XM"), 2);
+    }
+
+    #[test]
+    fn palindromes_count_once() {
+        // ABA reads the same both ways, so each line of it is one match
+        let input = "ABA
+BBB
+ABA
+";
+        let grid = parse_grid(input);
+        // two rows, two columns and both diagonals
+        assert_eq!(count_word(&grid, "ABA"), 6);
+        assert_eq!(count_word(&grid, "BBB"), 2);
+        assert_eq!(count_word(&grid, "AB"), 12);
+    }
+
+    #[test]
+    fn single_letters_count_once_per_cell() {
+        let grid = parse_grid("XMAS\nSAMX\n");
+        assert_eq!(count_word(&grid, "X"), 2);
+        assert_eq!(count_word(&grid, "Q"), 0);
+        assert_eq!(count_word(&grid, ""), 0);
+    }
+
+    #[test]
+    fn other_alphabets() {
+        let grid = parse_grid("αβγ\nβββ\nγβα\n");
+        assert_eq!(count_word(&grid, "αβγ"), 4);
+        assert_eq!(count_word(&grid, "γβα"), 4);
+        // each adjacent pair of βs, in any direction
+        assert_eq!(count_word(&grid, "ββ"), 8);
+    }
+
+    #[test]
+    fn check_example_x_mas() {
+        let input = "MMMSXXMASM
+MSAMXMSMSA
+AMXSXMAAMM
+MSAMASMSMX
+XMASAMXAMM
+XXAMMXXAMA
+SMSMSASXSS
+SAXAMASAAA
+MAMMMXMMMM
+MXMXAXMASX
+";
+        let grid = parse_grid(input);
+        assert_eq!(count_pattern(&grid, &Pattern::parse("M.S/.A./M.S")), 9);
+        assert_eq!(count_pattern(&grid, &Pattern::parse("S.S\n.A.\nM.M")), 9);
+    }
+
+    #[test]
+    fn orientations() {
+        // fully symmetric: only one way round
+        assert_eq!(Pattern::parse("A.A/.A./A.A").orientations().len(), 1);
+        // the X-MAS cross can be turned four ways, and mirroring adds nothing
+        assert_eq!(Pattern::parse("M.S/.A./M.S").orientations().len(), 4);
+        // a straight line lies either across or down
+        assert_eq!(Pattern::parse("ABA").orientations().len(), 2);
+        // an L-shape has all eight
+        assert_eq!(Pattern::parse("A./AB").orientations().len(), 8);
+    }
+
+    #[test]
+    fn pattern_at_edges() {
+        let grid = parse_grid("AB\nBA\n");
+        assert_eq!(count_pattern(&grid, &Pattern::parse("A./.A")), 1);
+        assert_eq!(count_pattern(&grid, &Pattern::parse("AB")), 4);
+        assert_eq!(count_pattern(&grid, &Pattern::parse("A.B")), 0);
+        assert_eq!(count_pattern(&grid, &Pattern::parse("A.")), 4);
+    }
+
+    #[test]
+    fn matches_offset_search() {
+        let words = ["XMAS", "SAMX", "MAS", "AMA", "X", "XX", "MASAM", "SS"];
+        for seed in 1..20 {
+            let grid = random_grid(seed, 7 + seed as usize % 5, 9, &['X', 'M', 'A', 'S']);
+            let counts = count_words(&find_words(&grid, &words), words.len());
+            for (word, count) in words.iter().zip(counts) {
+                assert_eq!(count, count_word_by_offsets(&grid, word), "{}", word);
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_and_reversed_words() {
+        let grid = parse_grid("XMASAMX\n");
+        assert_eq!(
+            count_words(&find_words(&grid, &["XMAS", "SAMX", "XMAS", ""]), 4),
+            vec![2, 2, 2, 0]
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_large_grid() {
+        let grid = random_grid(42, 1000, 1000, &['X', 'M', 'A', 'S']);
+        let words = ["XMAS", "MAS", "SAMX", "XMASX", "AMMA", "SAXS"];
+
+        let start = Instant::now();
+        let counts = count_words(&find_words(&grid, &words), words.len());
+        println!("aho-corasick, {} words: {:?}", words.len(), start.elapsed());
+
+        let start = Instant::now();
+        let expected = words
+            .iter()
+            .map(|word| count_word_by_offsets(&grid, word))
+            .collect::<Vec<_>>();
+        println!("offsets, {} words: {:?}", words.len(), start.elapsed());
+
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn match_locations() {
+        let grid = parse_grid("XMAS.\n.A...\nSAMX.\n.....\n");
+        let matches = find_words(&grid, &["XMAS", "AA"]);
+        assert_eq!(
+            matches,
+            vec![
+                WordMatch {
+                    start: (0, 0),
+                    direction: (0, 1),
+                    word: 0
+                },
+                WordMatch {
+                    start: (2, 3),
+                    direction: (0, -1),
+                    word: 0
+                },
+                WordMatch {
+                    start: (1, 1),
+                    direction: (1, 0),
+                    word: 1
+                },
+                WordMatch {
+                    start: (0, 2),
+                    direction: (1, -1),
+                    word: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn render_example() {
+        let input = "MMMSXXMASM
+MSAMXMSMSA
+AMXSXMAAMM
+MSAMASMSMX
+XMASAMXAMM
+XXAMMXXAMA
+SMSMSASXSS
+SAXAMASAAA
+MAMMMXMMMM
+MXMXAXMASX
+";
+        let expected = "....XXMAS.
+.SAMXMS...
+...S..A...
+..A.A.MS.X
+XMASAMX.MM
+X.....XA.A
+S.S.S.S.SS
+.A.A.A.A.A
+..M.M.M.MM
+.X.X.XMASX
+";
+        let grid = parse_grid(input);
+        let matches = find_words(&grid, &["XMAS"]);
+        assert_eq!(render(&grid, &["XMAS"], &matches, Render::Plain), expected);
     }
 }
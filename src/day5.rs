@@ -1,14 +1,51 @@
-use std::fs;
+use std::{fmt::Write, fs};
 
-pub(crate) fn day5(input_filename: &str) {
+mod rule_graph;
+
+use rule_graph::{OrderingError, RuleGraph};
+
+pub(crate) fn day5(
+    input_filename: &str,
+    explain: bool,
+    dot_filename: Option<&str>,
+    dot_update: Option<usize>,
+) {
     let contents = fs::read_to_string(input_filename).expect("could not read file");
-    let valid_updates = get_valid_updates(contents);
+    let (rules, updates) = parse_input(&contents);
+    let graph = RuleGraph::new(&rules);
+    let valid_updates = get_valid_updates(&updates, &graph);
+
+    if explain {
+        println!("{}", explain_updates(&rules, &updates, &graph));
+    }
+
+    if let Some(dot_filename) = dot_filename {
+        let update = dot_update.map(|n| updates.get(n).expect("no such update").as_slice());
+        fs::write(dot_filename, graph.to_dot(update)).expect("could not write DOT file");
+    }
 
-    let sum_of_middle_pages: u64 = valid_updates.iter().map(|update| update[update.len() / 2]).sum();
-    println!("sum of middle pages of valid updates: {}", sum_of_middle_pages);
+    if let Err(e) = graph.check_acyclic() {
+        println!("note: taken as a whole, the {}", e);
+    }
+
+    println!(
+        "sum of middle pages of valid updates: {}",
+        sum_of_middle_pages(&valid_updates)
+    );
+    match get_reordered_updates(&updates, &graph) {
+        Ok(reordered_updates) => println!(
+            "sum of middle pages of reordered updates: {}",
+            sum_of_middle_pages(&reordered_updates)
+        ),
+        Err(e) => println!("cannot reorder updates: {}", e),
+    }
 }
 
-fn get_valid_updates(input: String) -> Vec<Vec<u64>> {
+fn sum_of_middle_pages(updates: &[Vec<u64>]) -> u64 {
+    updates.iter().map(|update| update[update.len() / 2]).sum()
+}
+
+fn parse_input(input: &str) -> (Vec<Rule>, Vec<Vec<u64>>) {
     let rules = input
         .lines()
         .take_while(|&line| !line.is_empty())
@@ -33,21 +70,76 @@ fn get_valid_updates(input: String) -> Vec<Vec<u64>> {
         })
         .collect::<Vec<_>>();
 
-    println!("num rules: {}, num updates: {}", rules.len(), updates.len());
+    (rules, updates)
+}
 
+fn get_valid_updates(updates: &[Vec<u64>], graph: &RuleGraph) -> Vec<Vec<u64>> {
     let valid_updates = updates
         .iter()
-        .filter(|update| rules.iter().all(|rule| rule.is_update_valid(update)))
+        .filter(|update| graph.is_valid(update))
         .map(|update| update.to_owned())
         .collect::<Vec<_>>();
 
     valid_updates
 }
 
-#[derive(Debug)]
+/// Describes why each update is or isn't in the right order.
+fn explain_updates(rules: &[Rule], updates: &[Vec<u64>], graph: &RuleGraph) -> String {
+    let mut out = format!("{} rules, {} updates\n", rules.len(), updates.len());
+
+    for (n, update) in updates.iter().enumerate() {
+        let pages = update
+            .iter()
+            .map(|page| page.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let violations = graph.violations(update);
+
+        if violations.is_empty() {
+            let middle = update.len() / 2;
+            writeln!(
+                out,
+                "update {} ({}): valid, middle page {} at position {}",
+                n, pages, update[middle], middle
+            )
+            .unwrap();
+        } else {
+            writeln!(out, "update {} ({}): invalid", n, pages).unwrap();
+            for violation in violations {
+                let Rule(before, after) = violation.rule;
+                let (before_pos, after_pos) = violation.positions;
+                writeln!(
+                    out,
+                    "    breaks {}|{}: {} is at position {}, {} at {}",
+                    before, after, before, before_pos, after, after_pos
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    out
+}
+
+/// Puts every incorrectly-ordered update into the right order.
+fn get_reordered_updates(
+    updates: &[Vec<u64>],
+    graph: &RuleGraph,
+) -> Result<Vec<Vec<u64>>, OrderingError> {
+    updates
+        .iter()
+        .filter(|update| !graph.is_valid(update))
+        .map(|update| graph.order(update))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct Rule(u64, u64);
 
 impl Rule {
+    /// Scans the whole update for this one rule; kept as a reference for
+    /// `RuleGraph::is_valid`.
+    #[cfg(test)]
     fn is_update_valid(&self, update: &Vec<u64>) -> bool {
         let mut seen_first = false;
         let mut seen_second = false;
@@ -63,20 +155,81 @@ impl Rule {
                 seen_second = true;
             }
         }
-        return if seen_first && seen_second {
+        if seen_first && seen_second {
             !seen_second_before_first
         } else {
             true
-        };
+        }
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use super::{get_valid_updates, Rule};
+    use std::time::Instant;
 
-    #[test]
-    fn check_example() {
-        let input = "47|53
+    use super::{
+        explain_updates, get_reordered_updates, get_valid_updates, parse_input,
+        rule_graph::{OrderingError, RuleGraph, Violation},
+        sum_of_middle_pages, Rule,
+    };
+    use crate::test_util::XorShift;
+
+    fn valid_updates(input: &str) -> Vec<Vec<u64>> {
+        let (rules, updates) = parse_input(input);
+        get_valid_updates(&updates, &RuleGraph::new(&rules))
+    }
+
+    fn reordered_updates(input: &str) -> Result<Vec<Vec<u64>>, OrderingError> {
+        let (rules, updates) = parse_input(input);
+        get_reordered_updates(&updates, &RuleGraph::new(&rules))
+    }
+
+    fn reorder_update(rules: &[Rule], update: &[u64]) -> Vec<u64> {
+        RuleGraph::new(rules).order(update).unwrap()
+    }
+
+    /// Every pair of `num_pages` pages is ordered by some fixed shuffle of
+    /// them, and each update is a random selection of pages in random order.
+    fn random_puzzle(
+        seed: u64,
+        num_pages: u64,
+        num_updates: usize,
+        update_len: usize,
+    ) -> (Vec<Rule>, Vec<Vec<u64>>) {
+        let mut random = XorShift::new(seed);
+        let mut order = (10..10 + num_pages).collect::<Vec<_>>();
+        for i in (1..order.len()).rev() {
+            order.swap(i, random.below(i + 1));
+        }
+
+        let mut rules = vec![];
+        for i in 0..order.len() {
+            for j in i + 1..order.len() {
+                if random.below(2) == 0 {
+                    rules.push(Rule(order[i], order[j]));
+                }
+            }
+        }
+
+        let updates = (0..num_updates)
+            .map(|_| {
+                let mut pages = order.clone();
+                for i in (1..pages.len()).rev() {
+                    pages.swap(i, random.below(i + 1));
+                }
+                pages.truncate(update_len);
+                // keep some of them in a valid order
+                if random.below(2) == 0 {
+                    pages.sort_by_key(|page| order.iter().position(|p| p == page));
+                }
+                pages
+            })
+            .collect();
+
+        (rules, updates)
+    }
+
+    const EXAMPLE: &str = "47|53
 97|13
 97|61
 97|47
@@ -105,7 +258,10 @@ mod tests {
 61,13,29
 97,13,75,29,47
 ";
-        assert_eq!(get_valid_updates(input.to_string()).len(), 3);
+
+    #[test]
+    fn check_example() {
+        assert_eq!(valid_updates(EXAMPLE).len(), 3);
     }
 
     #[test]
@@ -114,4 +270,199 @@ mod tests {
         let update = vec![47, 53];
         assert!(r.is_update_valid(&update));
     }
+
+    #[test]
+    fn check_example_reordering() {
+        let reordered = reordered_updates(EXAMPLE).unwrap();
+        assert_eq!(
+            reordered,
+            vec![
+                vec![97, 75, 47, 61, 53],
+                vec![61, 29, 13],
+                vec![97, 75, 47, 29, 13],
+            ]
+        );
+        assert_eq!(sum_of_middle_pages(&reordered), 123);
+    }
+
+    #[test]
+    fn reordering_is_deterministic() {
+        // nothing relates 1, 2 and 3 to each other, so they keep their order
+        let rules = vec![Rule(9, 1), Rule(9, 3)];
+        assert_eq!(reorder_update(&rules, &[1, 2, 3, 9]), vec![2, 9, 1, 3]);
+        assert_eq!(reorder_update(&rules, &[3, 9, 2, 1]), vec![9, 3, 2, 1]);
+    }
+
+    #[test]
+    fn reordering_ignores_rules_for_other_pages() {
+        let (rules, _) = parse_input(EXAMPLE);
+        let update = [13, 29, 61];
+        assert_eq!(reorder_update(&rules, &update), vec![61, 29, 13]);
+    }
+
+    #[test]
+    fn example_rules_are_acyclic() {
+        let (rules, _) = parse_input(EXAMPLE);
+        assert_eq!(RuleGraph::new(&rules).check_acyclic(), Ok(()));
+    }
+
+    #[test]
+    fn shortest_cycle() {
+        // 1 -> 2 -> 3 -> 4 -> 1 and the shortcut 2 -> 4
+        let rules = vec![Rule(1, 2), Rule(2, 3), Rule(3, 4), Rule(4, 1), Rule(2, 4)];
+        let graph = RuleGraph::new(&rules);
+        assert_eq!(graph.shortest_cycle(), Some(vec![1, 2, 4]));
+        assert_eq!(
+            graph.check_acyclic().unwrap_err().to_string(),
+            "rules form a cycle: 1 -> 2 -> 4 -> 1"
+        );
+
+        // without page 4 there is nothing left to go round
+        assert_eq!(graph.restrict(&[1, 2, 3]).shortest_cycle(), None);
+
+        assert_eq!(
+            RuleGraph::new(&[Rule(5, 5)]).shortest_cycle(),
+            Some(vec![5])
+        );
+    }
+
+    #[test]
+    fn cycles_only_matter_within_an_update() {
+        // globally cyclic, like the real puzzle input
+        let rules = vec![Rule(1, 2), Rule(2, 3), Rule(3, 1)];
+        let graph = RuleGraph::new(&rules);
+        assert!(graph.check_acyclic().is_err());
+
+        assert_eq!(graph.order(&[3, 2]), Ok(vec![2, 3]));
+        assert_eq!(graph.order(&[3, 1]), Ok(vec![3, 1]));
+        assert_eq!(
+            graph.order(&[3, 2, 1, 4]),
+            Err(OrderingError::Cycle(vec![1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn repeated_pages_stay_together() {
+        let graph = RuleGraph::new(&[Rule(1, 2)]);
+        assert_eq!(graph.order(&[2, 1, 2, 1]), Ok(vec![1, 1, 2, 2]));
+        assert_eq!(graph.order(&[3, 2, 3, 1]), Ok(vec![3, 3, 1, 2]));
+        assert_eq!(
+            reordered_updates("1|2\n\n2,1,2,1\n"),
+            Ok(vec![vec![1, 1, 2, 2]])
+        );
+    }
+
+    #[test]
+    fn reordering_reports_cycles() {
+        let input = "1|2\n2|3\n3|1\n\n2,1\n3,2,1\n";
+        assert_eq!(
+            reordered_updates(input),
+            Err(OrderingError::Cycle(vec![1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn indexed_validation_matches_rule_scan() {
+        for seed in 1..10 {
+            let (rules, updates) = random_puzzle(seed, 12, 50, 5);
+            let graph = RuleGraph::new(&rules);
+            for update in updates {
+                assert_eq!(
+                    graph.is_valid(&update),
+                    rules.iter().all(|rule| rule.is_update_valid(&update)),
+                    "{:?}",
+                    update
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_validation_with_many_rules() {
+        let (rules, updates) = random_puzzle(7, 100, 5000, 23);
+        println!("{} rules, {} updates", rules.len(), updates.len());
+
+        let start = Instant::now();
+        let graph = RuleGraph::new(&rules);
+        let indexed = updates
+            .iter()
+            .filter(|update| graph.is_valid(update))
+            .count();
+        println!("rule graph: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let scanned = updates
+            .iter()
+            .filter(|update| rules.iter().all(|rule| rule.is_update_valid(update)))
+            .count();
+        println!("rule scan: {:?}", start.elapsed());
+
+        assert_eq!(indexed, scanned);
+    }
+
+    #[test]
+    fn dot_export() {
+        let rules = [Rule(1, 2), Rule(2, 3), Rule(1, 3), Rule(3, 4)];
+        let graph = RuleGraph::new(&rules);
+        assert_eq!(
+            graph.to_dot(None),
+            "digraph rules {\n    1 -> 2;\n    1 -> 3;\n    2 -> 3;\n    3 -> 4;\n}\n"
+        );
+        assert_eq!(
+            graph.to_dot(Some(&[3, 1, 2])),
+            "digraph rules {\n    3;\n    1;\n    2;\n    \
+             1 -> 2;\n    1 -> 3 [color=red, penwidth=2];\n    2 -> 3 [color=red, penwidth=2];\n}\n"
+        );
+    }
+
+    #[test]
+    fn violations() {
+        let (rules, _) = parse_input(EXAMPLE);
+        let graph = RuleGraph::new(&rules);
+        assert_eq!(graph.violations(&[75, 47, 61, 53, 29]), vec![]);
+        assert_eq!(
+            graph.violations(&[61, 13, 29]),
+            vec![Violation {
+                rule: Rule(29, 13),
+                positions: (2, 1)
+            }]
+        );
+        assert_eq!(
+            graph.violations(&[97, 13, 75, 29, 47]),
+            vec![
+                Violation {
+                    rule: Rule(75, 13),
+                    positions: (2, 1)
+                },
+                Violation {
+                    rule: Rule(29, 13),
+                    positions: (3, 1)
+                },
+                Violation {
+                    rule: Rule(47, 13),
+                    positions: (4, 1)
+                },
+                Violation {
+                    rule: Rule(47, 29),
+                    positions: (4, 3)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn explanation() {
+        let (rules, updates) = parse_input(EXAMPLE);
+        let explanation = explain_updates(&rules, &updates, &RuleGraph::new(&rules));
+        let lines = explanation.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "21 rules, 6 updates");
+        assert_eq!(
+            lines[1],
+            "update 0 (75,47,61,53,29): valid, middle page 61 at position 2"
+        );
+        assert_eq!(lines[4], "update 3 (75,97,47,61,53): invalid");
+        assert_eq!(lines[5], "    breaks 97|75: 97 is at position 1, 75 at 0");
+        assert_eq!(lines.len(), 13);
+    }
 }
@@ -0,0 +1,249 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque},
+    error::Error,
+    fmt::{Display, Write},
+};
+
+use super::Rule;
+
+/// Edge attributes for a rule an update breaks.
+const VIOLATION_STYLE: &str = "color=red, penwidth=2";
+
+/// The page-ordering rules as a directed graph, where an edge from `a` to
+/// `b` means page `a` must come before page `b`.
+#[derive(Debug)]
+pub(crate) struct RuleGraph {
+    successors: BTreeMap<u64, BTreeSet<u64>>,
+}
+
+/// A rule broken by an update, which put its second page first.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Violation {
+    pub(crate) rule: Rule,
+    /// Where the rule's first and second pages are in the update
+    pub(crate) positions: (usize, usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum OrderingError {
+    /// The rules require each page to come before the next, and the last
+    /// before the first, so no order can satisfy them.
+    Cycle(Vec<u64>),
+}
+
+impl Display for OrderingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderingError::Cycle(pages) => {
+                write!(f, "rules form a cycle: ")?;
+                for page in pages {
+                    write!(f, "{} -> ", page)?;
+                }
+                write!(f, "{}", pages[0])
+            }
+        }
+    }
+}
+
+impl Error for OrderingError {}
+
+impl RuleGraph {
+    pub(crate) fn new(rules: &[Rule]) -> Self {
+        let mut successors = BTreeMap::<u64, BTreeSet<u64>>::new();
+        for &Rule(before, after) in rules {
+            successors.entry(before).or_default().insert(after);
+            successors.entry(after).or_default();
+        }
+        Self { successors }
+    }
+
+    /// Checks that no page has a rule saying it belongs before any page
+    /// earlier in the update. That is one set lookup per pair of pages,
+    /// however many rules there are.
+    pub(crate) fn is_valid(&self, update: &[u64]) -> bool {
+        update.iter().enumerate().all(|(i, page)| {
+            self.successors
+                .get(page)
+                .is_none_or(|after| update[..i].iter().all(|earlier| !after.contains(earlier)))
+        })
+    }
+
+    /// Every rule the update breaks, in the order the offending pages appear.
+    pub(crate) fn violations(&self, update: &[u64]) -> Vec<Violation> {
+        let mut violations = vec![];
+        for (j, &page) in update.iter().enumerate() {
+            let Some(after) = self.successors.get(&page) else {
+                continue;
+            };
+            for (i, &earlier) in update[..j].iter().enumerate() {
+                if after.contains(&earlier) {
+                    violations.push(Violation {
+                        rule: Rule(page, earlier),
+                        positions: (j, i),
+                    });
+                }
+            }
+        }
+        violations.sort_by_key(|v| (v.positions.1, v.positions.0));
+        violations
+    }
+
+    /// Only the rules between the given pages.
+    pub(crate) fn restrict(&self, pages: &[u64]) -> Self {
+        let pages = pages.iter().collect::<BTreeSet<_>>();
+        let successors = self
+            .successors
+            .iter()
+            .filter(|(page, _)| pages.contains(page))
+            .map(|(&page, after)| {
+                let after = after.iter().filter(|p| pages.contains(p)).copied();
+                (page, after.collect())
+            })
+            .collect();
+        Self { successors }
+    }
+
+    pub(crate) fn check_acyclic(&self) -> Result<(), OrderingError> {
+        match self.shortest_cycle() {
+            Some(cycle) => Err(OrderingError::Cycle(cycle)),
+            None => Ok(()),
+        }
+    }
+
+    /// The cycle with the fewest pages, if there is one. Ties go to the
+    /// cycle through the lowest-numbered page, which is listed first.
+    pub(crate) fn shortest_cycle(&self) -> Option<Vec<u64>> {
+        let mut shortest: Option<Vec<u64>> = None;
+        for &start in self.successors.keys() {
+            if let Some(cycle) = self.shortest_cycle_through(start) {
+                if shortest.as_ref().is_none_or(|s| cycle.len() < s.len()) {
+                    shortest = Some(cycle);
+                }
+            }
+        }
+        shortest
+    }
+
+    /// Breadth-first search for the shortest way back to `start`.
+    fn shortest_cycle_through(&self, start: u64) -> Option<Vec<u64>> {
+        let mut came_from = HashMap::<u64, u64>::new();
+        let mut queue = VecDeque::from([start]);
+
+        while let Some(page) = queue.pop_front() {
+            for &next in &self.successors[&page] {
+                if next == start {
+                    let mut cycle = vec![page];
+                    while let Some(&prev) = came_from.get(cycle.last().unwrap()) {
+                        cycle.push(prev);
+                    }
+                    cycle.reverse();
+                    return Some(cycle);
+                }
+                if let Entry::Vacant(entry) = came_from.entry(next) {
+                    entry.insert(page);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The rules as a Graphviz digraph. Given an update, only the rules
+    /// between its pages are drawn, and the ones it breaks are highlighted.
+    pub(crate) fn to_dot(&self, update: Option<&[u64]>) -> String {
+        let mut dot = String::from("digraph rules {\n");
+
+        let (successors, broken) = match update {
+            Some(update) => {
+                for page in update {
+                    writeln!(dot, "    {};", page).unwrap();
+                }
+                let broken = self
+                    .violations(update)
+                    .into_iter()
+                    .map(|violation| violation.rule)
+                    .collect::<Vec<_>>();
+                (self.restrict(update).successors, broken)
+            }
+            None => (self.successors.clone(), vec![]),
+        };
+
+        for (before, after) in successors {
+            for after in after {
+                if broken.contains(&Rule(before, after)) {
+                    writeln!(dot, "    {} -> {} [{}];", before, after, VIOLATION_STYLE).unwrap();
+                } else {
+                    writeln!(dot, "    {} -> {};", before, after).unwrap();
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Sorts `pages` so that every rule between them holds.
+    ///
+    /// When the rules allow more than one order, pages keep their original
+    /// relative order wherever possible, so the result is always the same. A
+    /// page which appears more than once has all its copies kept together,
+    /// where its first copy would go.
+    pub(crate) fn order(&self, pages: &[u64]) -> Result<Vec<u64>, OrderingError> {
+        let graph = self.restrict(pages);
+        let (distinct, copies) = distinct_pages(pages);
+        let position = distinct
+            .iter()
+            .enumerate()
+            .map(|(i, &page)| (page, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut num_predecessors = vec![0usize; distinct.len()];
+        for after in graph.successors.values() {
+            for page in after {
+                num_predecessors[position[page]] += 1;
+            }
+        }
+
+        // Kahn's algorithm, always taking the earliest page which is ready
+        let mut ready = (0..distinct.len())
+            .filter(|&i| num_predecessors[i] == 0)
+            .collect::<BTreeSet<_>>();
+        let mut ordered = Vec::with_capacity(pages.len());
+        while let Some(i) = ready.pop_first() {
+            ordered.extend(std::iter::repeat_n(distinct[i], copies[i]));
+            for page in graph.successors.get(&distinct[i]).into_iter().flatten() {
+                let j = position[page];
+                num_predecessors[j] -= 1;
+                if num_predecessors[j] == 0 {
+                    ready.insert(j);
+                }
+            }
+        }
+
+        if ordered.len() < pages.len() {
+            // Kahn's algorithm only gets stuck on a cycle
+            return Err(OrderingError::Cycle(graph.shortest_cycle().unwrap()));
+        }
+        Ok(ordered)
+    }
+}
+
+/// The pages of an update in order of first appearance, and how many times
+/// each appears.
+fn distinct_pages(update: &[u64]) -> (Vec<u64>, Vec<usize>) {
+    let mut index = HashMap::new();
+    let mut distinct = vec![];
+    let mut copies = vec![];
+    for &page in update {
+        match index.entry(page) {
+            Entry::Occupied(i) => copies[*i.get()] += 1,
+            Entry::Vacant(entry) => {
+                entry.insert(distinct.len());
+                distinct.push(page);
+                copies.push(1);
+            }
+        }
+    }
+    (distinct, copies)
+}
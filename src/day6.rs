@@ -1,27 +1,150 @@
-use std::{collections::HashSet, fmt::{Display, Write}, fs};
+use std::{
+    collections::HashSet,
+    fmt::{Display, Write},
+    fs,
+    num::NonZeroUsize,
+    thread,
+};
 
 use grid::Grid;
 
-pub(crate) fn day6(input_filename: &str) {
+mod guards;
+mod jump_table;
+mod movement;
+mod obstacle_map;
+mod trace;
+
+pub(crate) use guards::CollisionPolicy;
+use guards::{patrol_alone, patrol_group, GroupEnding, GroupPatrol};
+use jump_table::JumpTable;
+use movement::MovementRule;
+pub(crate) use movement::Turn;
+use obstacle_map::{Cell, ObstacleMap};
+pub(crate) use trace::TraceFormat;
+use trace::{format_trace, render, trace};
+
+pub(crate) fn day6(
+    input_filename: &str,
+    threads: Option<NonZeroUsize>,
+    trace_format: Option<TraceFormat>,
+    show_route: bool,
+    turn: Turn,
+    seed: u64,
+    collisions: CollisionPolicy,
+) {
+    let threads =
+        threads.unwrap_or_else(|| thread::available_parallelism().unwrap_or(NonZeroUsize::MIN));
     let contents = fs::read_to_string(input_filename).expect("could not read file");
     let map = parse_map(contents);
-    let n_unique_positions = find_exit(map);
-    println!("unique positions: {}", n_unique_positions);
+    let obstacles = ObstacleMap::new(&map);
+    let rule = turn.rule(seed);
+    let rule = rule.as_ref();
+
+    let start = match map.get_guards()[..] {
+        [] => {
+            println!("no guard on the map");
+            return;
+        }
+        [start] => start,
+        ref guards => {
+            if trace_format.is_some() || show_route {
+                println!("note: routes can only be traced for a single guard");
+            }
+            print_group_patrol(guards, &patrol_group(&obstacles, rule, guards, collisions));
+            return;
+        }
+    };
+
+    if trace_format.is_some() || show_route {
+        let steps = trace(&obstacles, rule, start);
+        if let Some(format) = trace_format {
+            print!("{}", format_trace(&steps, format));
+        }
+        if show_route {
+            println!("{}", render(&map, &steps));
+        }
+    }
+
+    match find_exit(&obstacles, rule, start) {
+        PatrolOutcome::Exited { visited } => {
+            println!("unique positions: {}", visited.len());
+            println!(
+                "obstructions causing a loop: {}",
+                count_loop_obstructions(&obstacles, rule, start, &visited, threads.get())
+            );
+        }
+        PatrolOutcome::Looped {
+            cycle_start,
+            cycle_len,
+        } => println!(
+            "guard never leaves: from step {} they repeat a {}-step loop",
+            cycle_start, cycle_len
+        ),
+    }
 }
 
-fn find_exit(map: TileMap) -> usize {
-    let mut map = map;
-    let mut visited = HashSet::<(usize, usize)>::new();
-    visited.insert(map.get_guard_position());
-    loop {
-        // println!("move {}:\n{}\n", visited.len(), map);
-        let next = get_next_guard_position(&map);
-        if let Some((next_pos, direction)) = next {
-            visited.insert(next_pos);
-            map.move_guard(next_pos, direction);
-        } else {
-            return visited.len();
+fn print_group_patrol(starts: &[GuardState], patrol: &GroupPatrol) {
+    for (i, (start, guard)) in starts.iter().zip(&patrol.guards).enumerate() {
+        let status = match guard.left_at {
+            Some(step) => format!("left after {} steps", step),
+            None => "never leaves".to_string(),
+        };
+        println!(
+            "guard {} from {:?}: {} unique positions, {}",
+            i + 1,
+            start.0,
+            guard.visited.len(),
+            status
+        );
+    }
+    println!("unique positions, all guards: {}", patrol.coverage().len());
+    match patrol.ending {
+        GroupEnding::AllLeft => {}
+        GroupEnding::Cycle {
+            cycle_start,
+            cycle_len,
+        } => println!(
+            "from step {} the guards repeat a {}-step loop",
+            cycle_start, cycle_len
+        ),
+        GroupEnding::CycleTooLong => {
+            println!("the guards repeat themselves, but the loop is too long to count")
         }
+        GroupEnding::NoRepeatWithin(steps) => println!(
+            "gave up after {} steps without the guards repeating themselves; positions are counted up to there",
+            steps
+        ),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum PatrolOutcome {
+    /// The guard walked off the map, having been to these cells.
+    Exited { visited: HashSet<(usize, usize)> },
+    /// The guard got back to a cell facing the same way as at step
+    /// `cycle_start`, so repeats the same `cycle_len` steps forever.
+    Looped {
+        cycle_start: usize,
+        cycle_len: usize,
+    },
+}
+
+/// Follows the guard until they leave the map or repeat themselves.
+fn find_exit(map: &ObstacleMap, rule: &dyn MovementRule, start: GuardState) -> PatrolOutcome {
+    match patrol_alone(map, rule, start) {
+        (
+            _,
+            GroupEnding::Cycle {
+                cycle_start,
+                cycle_len,
+            },
+        ) => PatrolOutcome::Looped {
+            cycle_start,
+            cycle_len,
+        },
+        (patrol, _) => PatrolOutcome::Exited {
+            visited: patrol.visited,
+        },
     }
 }
 
@@ -32,7 +155,7 @@ fn parse_map(input: String) -> TileMap {
     let mut map = TileMap(Grid::new(size_x, size_y));
 
     for ((x, y), tile) in map.0.indexed_iter_mut() {
-        let ch = &lines[y][x..x+1];
+        let ch = &lines[y][x..x + 1];
         *tile = match ch {
             "." => TileType::Path,
             "#" => TileType::Obstacle,
@@ -40,6 +163,11 @@ fn parse_map(input: String) -> TileMap {
             ">" => TileType::Guard(Direction::Right),
             "<" => TileType::Guard(Direction::Left),
             "v" => TileType::Guard(Direction::Down),
+            "U" => TileType::OneWay(Direction::Up),
+            "R" => TileType::OneWay(Direction::Right),
+            "L" => TileType::OneWay(Direction::Left),
+            "D" => TileType::OneWay(Direction::Down),
+            _ if ch.as_bytes()[0].is_ascii_digit() => TileType::Teleporter(ch.as_bytes()[0]),
             _ => panic!("unknown tile: {}", ch),
         };
     }
@@ -47,27 +175,130 @@ fn parse_map(input: String) -> TileMap {
     map
 }
 
-fn get_next_guard_position(map: &TileMap) -> Option<((usize, usize), Direction)> {
-    let (guard_x, guard_y) = map.get_guard_position();
-    let (facing_pos,direction, tile) = map.sense_guard();
-    match tile {
-        Some(TileType::Path) => Some((facing_pos, direction)),
-        Some(TileType::Obstacle) => {
-            let turned_dir = direction.turn_right();
-            let next_pos = match turned_dir {
-                Direction::Up => (guard_x, guard_y - 1),
-                Direction::Left => (guard_x - 1, guard_y),
-                Direction::Down => (guard_x, guard_y + 1),
-                Direction::Right => (guard_x + 1, guard_y),
-            };
-            Some((next_pos, turned_dir))
-        },
-        None => None,
-        _ => panic!()
+/// Where the guard is and which way they're facing.
+type GuardState = ((usize, usize), Direction);
+
+/// Where the guard goes next, or `None` once they walk off the map. Faced
+/// with an obstacle, the guard turns as the rule says and steps that way, or
+/// just turns if that way is blocked too.
+fn next_guard_state(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    (position, direction): GuardState,
+    extra_obstacle: Option<(usize, usize)>,
+) -> Option<GuardState> {
+    let is_blocked = |pos, direction| map.blocks(pos, direction) || Some(pos) == extra_obstacle;
+
+    let ahead = map.step(position, direction)?;
+    if !is_blocked(ahead, direction) {
+        return Some((map.arrive(ahead), direction));
+    }
+    let turned = rule.turn((position, direction));
+    let side = map.step(position, turned)?;
+    if is_blocked(side, turned) {
+        Some((position, turned))
+    } else {
+        Some((map.arrive(side), turned))
+    }
+}
+
+/// Counts the cells where one more obstacle would send the guard round in a
+/// loop. Only open cells on the guard's original route can change it, and
+/// the guard's starting cell is ruled out because they would see it placed.
+///
+/// The candidates are sorted by position and shared out between `threads`
+/// threads, each taking every `threads`th one, so that every thread gets
+/// cells from all over the map rather than one region of it.
+fn count_loop_obstructions(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    start: GuardState,
+    route: &HashSet<(usize, usize)>,
+    threads: usize,
+) -> usize {
+    let jumps = JumpTable::new(map);
+    let mut candidates = route
+        .iter()
+        .copied()
+        .filter(|&obstacle| obstacle != start.0 && map.cell(obstacle) == Cell::Open)
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+
+    let threads = threads.clamp(1, candidates.len().max(1));
+    if threads == 1 {
+        return candidates
+            .iter()
+            .filter(|&&obstacle| is_loop(map, &jumps, rule, start, obstacle))
+            .count();
+    }
+
+    thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|first| {
+                let (jumps, candidates) = (&jumps, &candidates);
+                scope.spawn(move || {
+                    candidates
+                        .iter()
+                        .skip(first)
+                        .step_by(threads)
+                        .filter(|&&obstacle| is_loop(map, jumps, rule, start, obstacle))
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("loop search thread panicked"))
+            .sum()
+    })
+}
+
+/// Whether one more obstacle leaves the guard going round in a loop. Every
+/// loop has a turn or a teleport in it, so the guard jumps from one of those
+/// to the next and only the states after them need remembering.
+fn is_loop(
+    map: &ObstacleMap,
+    jumps: &JumpTable,
+    rule: &dyn MovementRule,
+    start: GuardState,
+    obstacle: (usize, usize),
+) -> bool {
+    let mut seen = HashSet::new();
+    let (mut position, mut direction) = start;
+    while let Some(stop) = jumps.jump(position, direction, Some(obstacle)) {
+        if stop != position && matches!(map.cell(stop), Cell::Teleporter(_)) {
+            position = map.arrive(stop);
+        } else {
+            direction = rule.turn((stop, direction));
+            position = stop;
+        }
+        if !seen.insert((position, direction)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// The same as `is_loop`, one step at a time.
+#[cfg(test)]
+fn is_loop_by_steps(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    start: GuardState,
+    obstacle: (usize, usize),
+) -> bool {
+    let mut seen = HashSet::from([start]);
+    let mut state = start;
+    while let Some(next) = next_guard_state(map, rule, state, Some(obstacle)) {
+        if !seen.insert(next) {
+            return true;
+        }
+        state = next;
     }
+    false
 }
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 enum Direction {
     Up,
     Left,
@@ -84,24 +315,69 @@ impl Direction {
             Direction::Left => Direction::Up,
         }
     }
+
+    /// The neighbouring cell this way, if it isn't off the top or left edge.
+    fn step(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        match self {
+            Direction::Up => Some((x, y.checked_sub(1)?)),
+            Direction::Left => Some((x.checked_sub(1)?, y)),
+            Direction::Down => Some((x, y + 1)),
+            Direction::Right => Some((x + 1, y)),
+        }
+    }
+
+    /// How many steps this way it is from `from` to `to`, if `to` is ahead.
+    fn steps_to(&self, from: (usize, usize), to: (usize, usize)) -> Option<usize> {
+        let ((from_x, from_y), (to_x, to_y)) = (from, to);
+        match self {
+            Direction::Up => (to_x == from_x && to_y < from_y).then(|| from_y - to_y),
+            Direction::Left => (to_y == from_y && to_x < from_x).then(|| from_x - to_x),
+            Direction::Down => (to_x == from_x && to_y > from_y).then(|| to_y - from_y),
+            Direction::Right => (to_y == from_y && to_x > from_x).then(|| to_x - from_x),
+        }
+    }
+
+    /// The cell `distance` steps this way, which must not be off the map.
+    fn advance(&self, (x, y): (usize, usize), distance: usize) -> (usize, usize) {
+        match self {
+            Direction::Up => (x, y - distance),
+            Direction::Left => (x - distance, y),
+            Direction::Down => (x, y + distance),
+            Direction::Right => (x + distance, y),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Left => "left",
+            Direction::Down => "down",
+            Direction::Right => "right",
+        }
+    }
+
+    /// Numbers the directions from 0 to 3.
+    fn index(&self) -> usize {
+        *self as usize
+    }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Default)]
 enum TileType {
     Guard(Direction),
     Obstacle,
+    /// Can only be entered going this way
+    OneWay(Direction),
+    /// One of a pair of pads with the same label, which take the guard from
+    /// one to the other
+    Teleporter(u8),
+    #[default]
     Path,
 }
 
-impl Default for TileType {
-    fn default() -> Self {
-        TileType::Path
-    }
-}
-
 struct TileMap(Grid<TileType>);
 
-impl<'a> Display for TileMap {
+impl Display for TileMap {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for col in self.0.iter_cols() {
             for tile in col {
@@ -112,6 +388,11 @@ impl<'a> Display for TileMap {
                     TileType::Guard(Direction::Left) => '<',
                     TileType::Guard(Direction::Down) => 'v',
                     TileType::Guard(Direction::Right) => '>',
+                    TileType::OneWay(Direction::Up) => 'U',
+                    TileType::OneWay(Direction::Left) => 'L',
+                    TileType::OneWay(Direction::Down) => 'D',
+                    TileType::OneWay(Direction::Right) => 'R',
+                    TileType::Teleporter(label) => label as char,
                 };
                 f.write_char(ch)?;
             }
@@ -122,52 +403,99 @@ impl<'a> Display for TileMap {
 }
 
 impl TileMap {
-    fn get_guard_position(&self) -> (usize, usize) {
-        let (guard_pos, _) = self.0.indexed_iter().find(|&(_, tile)| match tile {
-            TileType::Guard(..) => true,
-            _ => false
-        }).expect("guard not on map!");
-        guard_pos    
-    }
-
-    fn sense_guard(&self) -> ((usize, usize), Direction, Option<&TileType>) {
-        let (guard_x, guard_y) = self.get_guard_position();
-        let guard_tile = self.0.get(guard_x, guard_y).unwrap();
-        let (sense_x, sense_y) = match guard_tile {
-            TileType::Guard(Direction::Up) => (guard_x, guard_y - 1),
-            TileType::Guard(Direction::Left) => (guard_x - 1, guard_y),
-            TileType::Guard(Direction::Down) => (guard_x, guard_y + 1),
-            TileType::Guard(Direction::Right) => (guard_x + 1, guard_y),
-            _ => panic!("no guard at guard_pos!")
-        };
-        if let TileType::Guard(direction) = guard_tile {
-            ((sense_x, sense_y), direction.clone(), self.0.get(sense_x, sense_y))
-        } else {
-            panic!();
-        }
+    /// Every guard on the map, in reading order.
+    fn get_guards(&self) -> Vec<GuardState> {
+        let mut guards = self
+            .0
+            .indexed_iter()
+            .filter_map(|(position, tile)| match tile {
+                TileType::Guard(direction) => Some((position, *direction)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        guards.sort_by_key(|&((x, y), _)| (y, x));
+        guards
     }
 
-    fn move_guard(&mut self, (new_x, new_y): (usize, usize), direction: Direction) {
-        let (cur_x, cur_y) = self.get_guard_position();
-        let mut cur_row = self.0.remove_row(cur_x).unwrap();
-        cur_row[cur_y] = TileType::Path;
-        self.0.insert_row(cur_x, cur_row);
-        let mut new_row = self.0.remove_row(new_x).unwrap();
-        new_row[new_y] = TileType::Guard(direction);
-        self.0.insert_row(new_x, new_row);
+    /// The only guard on the map.
+    #[cfg(test)]
+    fn get_guard(&self) -> GuardState {
+        match self.get_guards()[..] {
+            [guard] => guard,
+            ref guards => panic!("expected one guard, found {}", guards.len()),
+        }
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use grid::Grid;
+    use std::{collections::HashSet, time::Instant};
 
-    use crate::day6::{get_next_guard_position, Direction, TileType};
+    use crate::day6::{Direction, TileType};
 
-    use super::{find_exit, parse_map, TileMap};
+    use super::{
+        count_loop_obstructions, find_exit, is_loop, is_loop_by_steps, next_guard_state, parse_map,
+        GuardState, JumpTable, ObstacleMap, PatrolOutcome, TileMap,
+    };
+    use crate::day6::{
+        movement::{MovementRule, RandomTurn, Reverse, TurnLeft, TurnRight},
+        obstacle_map::Cell,
+        trace::{format_trace, render, trace, TraceFormat, TraceStep},
+    };
+    use crate::test_util::XorShift;
 
-    #[test]
-    fn check_example_map() {
-        let input = "....#.....
+    fn get_next_guard_position(map: &TileMap) -> Option<GuardState> {
+        next_guard_state(&ObstacleMap::new(map), &TurnRight, map.get_guard(), None)
+    }
+
+    fn run_patrol(map: &TileMap) -> PatrolOutcome {
+        find_exit(&ObstacleMap::new(map), &TurnRight, map.get_guard())
+    }
+
+    fn count_visited(map: &TileMap) -> usize {
+        match run_patrol(map) {
+            PatrolOutcome::Exited { visited } => visited.len(),
+            looped => panic!("guard didn't leave: {:?}", looped),
+        }
+    }
+
+    fn count_obstructions(map: &TileMap) -> usize {
+        match run_patrol(map) {
+            PatrolOutcome::Exited { visited } => count_loop_obstructions(
+                &ObstacleMap::new(map),
+                &TurnRight,
+                map.get_guard(),
+                &visited,
+                1,
+            ),
+            looped => panic!("guard didn't leave: {:?}", looped),
+        }
+    }
+
+    /// A square map with about one cell in `sparsity` blocked, and the guard
+    /// in the middle facing up.
+    fn random_map(seed: u64, size: usize, sparsity: u64) -> String {
+        let mut random = XorShift::new(seed);
+        let mut rows = (0..size)
+            .map(|_| {
+                (0..size)
+                    .map(|_| {
+                        if random.next_u64().is_multiple_of(sparsity) {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        rows[size / 2][size / 2] = '^';
+        rows.iter()
+            .map(|row| row.iter().collect::<String>() + "\n")
+            .collect()
+    }
+
+    const EXAMPLE: &str = "....#.....
 .........#
 ..........
 ..#.......
@@ -178,6 +506,10 @@ mod tests {
 #.........
 ......#...
 ";
+
+    #[test]
+    fn check_example_map() {
+        let input = EXAMPLE;
         let map = parse_map(input.to_string());
         let formatted = format!("{}", map);
         assert_eq!(*map.0.get(4, 6).unwrap(), TileType::Guard(Direction::Up));
@@ -199,25 +531,447 @@ mod tests {
     fn find_exit_2x2() {
         let input = ">#\n.#";
         let map = parse_map(input.to_string());
-        let num_moves = find_exit(map);
+        let num_moves = count_visited(&map);
         assert_eq!(num_moves, 2);
     }
 
     #[test]
     fn find_exit_example() {
-        let input = "....#.....
-.........#
-..........
-..#.......
-.......#..
-..........
-.#..^.....
-........#.
-#.........
-......#...
-";
+        let input = EXAMPLE;
         let map = parse_map(input.to_string());
-        let num_moves = find_exit(map);
+        let num_moves = count_visited(&map);
         assert_eq!(num_moves, 41);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn turn_in_place_when_boxed_in() {
+        let map = parse_map(".#\n>#\n#.\n".to_string());
+        assert_eq!(
+            get_next_guard_position(&map),
+            Some(((0, 1), Direction::Down))
+        );
+        let map = parse_map(".#.\n.^#\n...\n".to_string());
+        assert_eq!(
+            get_next_guard_position(&map),
+            Some(((1, 1), Direction::Right))
+        );
+    }
+
+    #[test]
+    fn exit_over_top_edge() {
+        let map = parse_map("^.\n..".to_string());
+        assert_eq!(get_next_guard_position(&map), None);
+        assert_eq!(count_visited(&map), 1);
+    }
+
+    #[test]
+    fn loop_obstructions_example() {
+        let map = parse_map(EXAMPLE.to_string());
+        assert_eq!(count_obstructions(&map), 6);
+    }
+
+    #[test]
+    fn no_loops_on_a_straight_run() {
+        let map = parse_map("...\n.^.\n...\n".to_string());
+        assert_eq!(count_obstructions(&map), 0);
+    }
+
+    #[test]
+    fn detect_loop_from_start() {
+        let map = parse_map(".#..\n.^.#\n#...\n..#.\n".to_string());
+        assert_eq!(
+            run_patrol(&map),
+            PatrolOutcome::Looped {
+                cycle_start: 0,
+                cycle_len: 4
+            }
+        );
+    }
+
+    #[test]
+    fn detect_loop_after_lead_in() {
+        let map = parse_map(".#..\n...#\n#...\n.^#.\n".to_string());
+        assert_eq!(
+            run_patrol(&map),
+            PatrolOutcome::Looped {
+                cycle_start: 2,
+                cycle_len: 4
+            }
+        );
+    }
+
+    #[test]
+    fn detect_loop_when_boxed_in() {
+        let map = parse_map(".#.\n#^#\n.#.\n".to_string());
+        assert_eq!(
+            run_patrol(&map),
+            PatrolOutcome::Looped {
+                cycle_start: 0,
+                cycle_len: 4
+            }
+        );
+    }
+
+    #[test]
+    fn exit_over_bottom_right_corner() {
+        let map = parse_map("..\n.>".to_string());
+        assert_eq!(get_next_guard_position(&map), None);
+        let map = parse_map("..\n.v".to_string());
+        assert_eq!(get_next_guard_position(&map), None);
+    }
+
+    /// Turns some of the obstacles in a random map into one-way tiles, and
+    /// adds a pair of teleporters.
+    fn add_fixtures(text: &str, seed: u64) -> String {
+        let mut random = XorShift::new(seed);
+        let mut rows = text
+            .lines()
+            .map(|line| line.chars().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for cell in rows.iter_mut().flatten().filter(|cell| **cell == '#') {
+            *cell = *random.pick(&['#', '#', 'U', 'L', 'D', 'R']);
+        }
+        let size = rows.len();
+        rows[size / 3][1] = '1';
+        rows[2 * size / 3][size - 2] = '1';
+        rows.iter()
+            .map(|row| row.iter().collect::<String>() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn remembering_turns_finds_the_same_loops() {
+        let rules: [&dyn MovementRule; 4] =
+            [&TurnRight, &TurnLeft, &Reverse, &RandomTurn { seed: 7 }];
+        for seed in 1..20 {
+            let mut text = random_map(seed, 20, 6);
+            if seed % 2 == 0 {
+                text = add_fixtures(&text, seed);
+            }
+            let map = parse_map(text.clone());
+            let obstacles = ObstacleMap::new(&map);
+            let jumps = JumpTable::new(&obstacles);
+            let start = map.get_guard();
+            for (r, &rule) in rules.iter().enumerate() {
+                for cell in 0..obstacles.num_cells() {
+                    let obstacle = obstacles.position(cell);
+                    if obstacle == start.0 || obstacles.cell(obstacle) != Cell::Open {
+                        continue;
+                    }
+                    let mut with_obstacle = parse_map(text.clone());
+                    *with_obstacle.0.get_mut(obstacle.0, obstacle.1).unwrap() = TileType::Obstacle;
+                    let looped = matches!(
+                        find_exit(&ObstacleMap::new(&with_obstacle), rule, start),
+                        PatrolOutcome::Looped { .. }
+                    );
+                    assert_eq!(
+                        is_loop_by_steps(&obstacles, rule, start, obstacle),
+                        looped,
+                        "seed {}, rule {}, obstacle at {:?}",
+                        seed,
+                        r,
+                        obstacle
+                    );
+                    assert_eq!(
+                        is_loop(&obstacles, &jumps, rule, start, obstacle),
+                        looped,
+                        "seed {}, rule {}, obstacle at {:?}",
+                        seed,
+                        r,
+                        obstacle
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_large_map() {
+        let map = parse_map(random_map(42, 1000, 100));
+
+        let start = Instant::now();
+        let obstacles = ObstacleMap::new(&map);
+        let guard = map.get_guard();
+        println!("setup, 1000x1000: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let outcome = find_exit(&obstacles, &TurnRight, guard);
+        println!("patrol, 1000x1000: {:?}", start.elapsed());
+
+        let PatrolOutcome::Exited { visited } = outcome else {
+            panic!("guard didn't leave: {:?}", outcome);
+        };
+        let start = Instant::now();
+        let by_steps = visited
+            .iter()
+            .filter(|&&obstacle| obstacle != guard.0)
+            .filter(|&&obstacle| is_loop_by_steps(&obstacles, &TurnRight, guard, obstacle))
+            .count();
+        println!(
+            "stepping through {} candidates: {:?}",
+            visited.len(),
+            start.elapsed()
+        );
+
+        let start = Instant::now();
+        let count = count_loop_obstructions(&obstacles, &TurnRight, guard, &visited, 1);
+        println!(
+            "jumping through {} candidates: {:?}",
+            visited.len(),
+            start.elapsed()
+        );
+        assert_eq!(count, by_steps);
+
+        for threads in [2, 4, 8] {
+            let start = Instant::now();
+            let parallel =
+                count_loop_obstructions(&obstacles, &TurnRight, guard, &visited, threads);
+            println!("jumping on {} threads: {:?}", threads, start.elapsed());
+            assert_eq!(parallel, count);
+        }
+    }
+
+    #[test]
+    fn jump_to_next_turn() {
+        let map = parse_map("..#..\n.....\n#.^.#\n.....\n..#..\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let jumps = JumpTable::new(&obstacles);
+        assert_eq!(jumps.jump((2, 2), Direction::Up, None), Some((2, 1)));
+        assert_eq!(jumps.jump((2, 2), Direction::Left, None), Some((1, 2)));
+        assert_eq!(jumps.jump((2, 2), Direction::Down, None), Some((2, 3)));
+        assert_eq!(jumps.jump((2, 2), Direction::Right, None), Some((3, 2)));
+        assert_eq!(jumps.jump((1, 1), Direction::Up, None), None);
+        assert_eq!(jumps.jump((1, 1), Direction::Right, None), None);
+        assert_eq!(jumps.jump((3, 2), Direction::Right, None), Some((3, 2)));
+    }
+
+    #[test]
+    fn jump_with_extra_obstacle() {
+        let map = parse_map(".....\n^...#\n.....\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let jumps = JumpTable::new(&obstacles);
+        let from = (0, 1);
+        assert_eq!(jumps.jump(from, Direction::Right, None), Some((3, 1)));
+        assert_eq!(
+            jumps.jump(from, Direction::Right, Some((2, 1))),
+            Some((1, 1))
+        );
+        assert_eq!(
+            jumps.jump(from, Direction::Right, Some((1, 1))),
+            Some((0, 1))
+        );
+        assert_eq!(
+            jumps.jump(from, Direction::Right, Some((2, 0))),
+            Some((3, 1))
+        );
+        assert_eq!(jumps.jump(from, Direction::Up, None), None);
+        assert_eq!(
+            jumps.jump((0, 2), Direction::Up, Some((0, 0))),
+            Some((0, 1))
+        );
+        assert_eq!(jumps.jump(from, Direction::Left, Some((1, 1))), None);
+    }
+
+    #[test]
+    fn threads_agree_with_sequential_search() {
+        for seed in 1..10 {
+            let map = parse_map(random_map(seed, 40, 8));
+            let obstacles = ObstacleMap::new(&map);
+            let start = map.get_guard();
+            let PatrolOutcome::Exited { visited } = find_exit(&obstacles, &TurnRight, start) else {
+                continue;
+            };
+            let sequential = count_loop_obstructions(&obstacles, &TurnRight, start, &visited, 1);
+            for threads in [0, 2, 3, 7, 1000] {
+                assert_eq!(
+                    count_loop_obstructions(&obstacles, &TurnRight, start, &visited, threads),
+                    sequential,
+                    "seed {}, {} threads",
+                    seed,
+                    threads
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trace_turns() {
+        let map = parse_map("#...\n...#\n^...\n".to_string());
+        let steps = trace(&ObstacleMap::new(&map), &TurnRight, map.get_guard());
+        let step = |position, direction, turn| TraceStep {
+            position,
+            direction,
+            turn,
+        };
+        assert_eq!(
+            steps,
+            vec![
+                step((0, 2), Direction::Up, false),
+                step((0, 1), Direction::Up, true),
+                step((1, 1), Direction::Right, false),
+                step((2, 1), Direction::Right, true),
+                step((2, 2), Direction::Down, false),
+            ]
+        );
+        assert_eq!(
+            format_trace(&steps[..2], TraceFormat::Csv),
+            "step,x,y,direction,turn\n0,0,2,up,false\n1,0,1,up,true\n"
+        );
+        assert_eq!(
+            format_trace(&steps[..2], TraceFormat::Json),
+            "[{\"step\":0,\"x\":0,\"y\":2,\"direction\":\"up\",\"turn\":false},\
+             {\"step\":1,\"x\":0,\"y\":1,\"direction\":\"up\",\"turn\":true}]\n"
+        );
+        assert_eq!(render(&map, &steps), "#...\n+-+#\n^.|.\n");
+    }
+
+    #[test]
+    fn trace_stops_before_repeating() {
+        let map = parse_map(".#..\n...#\n#...\n.^#.\n".to_string());
+        let steps = trace(&ObstacleMap::new(&map), &TurnRight, map.get_guard());
+        assert_eq!(steps.len(), 6);
+        assert!(steps.last().unwrap().turn);
+        assert_eq!(render(&map, &steps), ".#..\n.++#\n#++.\n.^#.\n");
+    }
+
+    #[test]
+    fn render_example_route() {
+        let map = parse_map(EXAMPLE.to_string());
+        let steps = trace(&ObstacleMap::new(&map), &TurnRight, map.get_guard());
+        assert_eq!(
+            render(&map, &steps),
+            "....#.....
+....+---+#
+....|...|.
+..#.|...|.
+..+-+-+#|.
+..|.|.|.|.
+.#+-^-+-+.
+.+----++#.
+#+----+|..
+......#|..
+"
+        );
+    }
+
+    #[test]
+    fn movement_rules() {
+        let state = ((3, 3), Direction::Up);
+        assert_eq!(TurnRight.turn(state), Direction::Right);
+        assert_eq!(TurnLeft.turn(state), Direction::Left);
+        assert_eq!(Reverse.turn(state), Direction::Down);
+
+        let random = RandomTurn { seed: 1 };
+        let turns = (0..100)
+            .map(|x| random.turn(((x, 0), Direction::Up)))
+            .collect::<Vec<_>>();
+        assert!(turns.iter().all(|&turn| turn != Direction::Up));
+        for direction in [Direction::Left, Direction::Down, Direction::Right] {
+            assert!(turns.contains(&direction));
+        }
+        let again = (0..100)
+            .map(|x| random.turn(((x, 0), Direction::Up)))
+            .collect::<Vec<_>>();
+        assert_eq!(turns, again);
+    }
+
+    #[test]
+    fn turn_left_around_the_example() {
+        let map = parse_map(EXAMPLE.to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let steps = trace(&obstacles, &TurnLeft, map.get_guard());
+        assert_eq!(steps[5].position, (4, 1));
+        assert!(steps[5].turn);
+        assert_eq!(steps[6].position, (3, 1));
+        assert_eq!(steps[6].direction, Direction::Left);
+    }
+
+    #[test]
+    fn reverse_walks_back_out() {
+        let map = parse_map("#\n.\n^\n".to_string());
+        let outcome = find_exit(&ObstacleMap::new(&map), &Reverse, map.get_guard());
+        assert_eq!(
+            outcome,
+            PatrolOutcome::Exited {
+                visited: HashSet::from([(0, 1), (0, 2)])
+            }
+        );
+    }
+
+    #[test]
+    fn one_way_tiles() {
+        // The guard can walk up onto `U` but `D` blocks them
+        let map = parse_map(".U.\n...\n.^.\n".to_string());
+        assert_eq!(count_visited(&map), 3);
+        let map = parse_map(".D.\n.^.\n".to_string());
+        assert_eq!(
+            get_next_guard_position(&map),
+            Some(((2, 1), Direction::Right))
+        );
+        assert_eq!(count_visited(&map), 2);
+        assert_eq!(map.to_string(), ".D.\n.^.\n");
+    }
+
+    #[test]
+    fn teleporters() {
+        let map = parse_map(".1.\n...\n1.<\n".to_string());
+        let steps = trace(&ObstacleMap::new(&map), &TurnRight, map.get_guard());
+        let positions = steps.iter().map(|step| step.position).collect::<Vec<_>>();
+        assert_eq!(positions, vec![(2, 2), (1, 2), (1, 0), (0, 0)]);
+        assert_eq!(render(&map, &steps), "-1.\n...\n1-<\n");
+    }
+
+    #[test]
+    fn teleporter_loop_without_turns() {
+        let map = parse_map("1>.1\n....\n".to_string());
+        assert_eq!(
+            run_patrol(&map),
+            PatrolOutcome::Looped {
+                cycle_start: 0,
+                cycle_len: 3
+            }
+        );
+        let obstacles = ObstacleMap::new(&map);
+        let jumps = JumpTable::new(&obstacles);
+        assert!(is_loop(
+            &obstacles,
+            &jumps,
+            &TurnRight,
+            map.get_guard(),
+            (0, 1)
+        ));
+    }
+
+    #[test]
+    fn jumps_stop_at_fixtures() {
+        let map = parse_map("......\n>..L.1\n.1....\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let jumps = JumpTable::new(&obstacles);
+        assert_eq!(jumps.jump((0, 1), Direction::Right, None), Some((2, 1)));
+        assert_eq!(jumps.jump((5, 1), Direction::Left, None), None);
+        assert_eq!(jumps.jump((4, 1), Direction::Right, None), Some((5, 1)));
+        assert_eq!(jumps.jump((4, 2), Direction::Left, None), Some((1, 2)));
+        assert_eq!(jumps.jump((1, 2), Direction::Left, None), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "teleporter 2 needs exactly two pads")]
+    fn unpaired_teleporter() {
+        ObstacleMap::new(&parse_map(".2.\n.^.\n".to_string()));
+    }
+
+    #[test]
+    fn guards_in_reading_order() {
+        let map = parse_map("..v\n>..\n.^.\n".to_string());
+        assert_eq!(
+            map.get_guards(),
+            vec![
+                ((2, 0), Direction::Down),
+                ((0, 1), Direction::Right),
+                ((1, 2), Direction::Up),
+            ]
+        );
+        assert_eq!(parse_map("..\n..\n".to_string()).get_guards(), vec![]);
+    }
+}
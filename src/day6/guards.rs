@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use clap::ValueEnum;
+
+use super::{movement::MovementRule, next_guard_state, obstacle_map::ObstacleMap, GuardState};
+
+/// How many steps guards who might get in each other's way are followed for
+/// before giving up on finding them repeat themselves.
+const MAX_LOCKSTEP_STEPS: usize = 10_000_000;
+
+/// What happens when guards patrolling together get in each other's way.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum CollisionPolicy {
+    /// Guards walk through each other as if they were alone.
+    Ignore,
+    /// A guard waits rather than step onto a cell where another guard is
+    /// standing, or which a guard earlier in reading order is stepping onto.
+    Wait,
+}
+
+/// How one guard got on.
+#[derive(Debug, PartialEq)]
+pub(crate) struct GuardPatrol {
+    pub(crate) visited: HashSet<(usize, usize)>,
+    /// The step at which the guard walked off the map, if they did
+    pub(crate) left_at: Option<usize>,
+}
+
+/// How a patrol by several guards ends, if it does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GroupEnding {
+    /// Every guard walked off the map.
+    AllLeft,
+    /// From step `cycle_start` the guards together repeat the same
+    /// `cycle_len` steps forever.
+    Cycle {
+        cycle_start: usize,
+        cycle_len: usize,
+    },
+    /// The guards repeat themselves, but the loop is too long to count.
+    CycleTooLong,
+    /// The guards hadn't repeated themselves after this many steps, so the
+    /// search gave up.
+    NoRepeatWithin(usize),
+}
+
+impl GroupEnding {
+    /// How two groups of guards who never meet end up, taken together.
+    fn combine(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::NoRepeatWithin(a), Self::NoRepeatWithin(b)) => Self::NoRepeatWithin(a.min(b)),
+            (Self::NoRepeatWithin(steps), _) | (_, Self::NoRepeatWithin(steps)) => {
+                Self::NoRepeatWithin(steps)
+            }
+            (Self::CycleTooLong, _) | (_, Self::CycleTooLong) => Self::CycleTooLong,
+            (Self::AllLeft, ending) | (ending, Self::AllLeft) => ending,
+            (
+                Self::Cycle {
+                    cycle_start: start_a,
+                    cycle_len: len_a,
+                },
+                Self::Cycle {
+                    cycle_start: start_b,
+                    cycle_len: len_b,
+                },
+            ) => match lcm(len_a, len_b) {
+                Some(cycle_len) => Self::Cycle {
+                    cycle_start: start_a.max(start_b),
+                    cycle_len,
+                },
+                None => Self::CycleTooLong,
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct GroupPatrol {
+    /// One for each guard, in the order they were given
+    pub(crate) guards: Vec<GuardPatrol>,
+    pub(crate) ending: GroupEnding,
+}
+
+impl GroupPatrol {
+    /// Every cell any guard has been to.
+    pub(crate) fn coverage(&self) -> HashSet<(usize, usize)> {
+        self.guards
+            .iter()
+            .flat_map(|guard| guard.visited.iter().copied())
+            .collect()
+    }
+}
+
+/// Moves all the guards one step at a time, in lockstep, until every one has
+/// left the map or together they are back in a state they were in before.
+///
+/// A waiting guard is only ever held back along their own route, so guards
+/// whose routes never cross can't get in each other's way. Each group of
+/// guards who might is followed on its own, and the groups' loops combined,
+/// rather than waiting for every guard on the map to repeat at once.
+pub(crate) fn patrol_group(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    starts: &[GuardState],
+    policy: CollisionPolicy,
+) -> GroupPatrol {
+    let mut alone = starts
+        .iter()
+        .map(|&start| Some(patrol_alone(map, rule, start)))
+        .collect::<Vec<_>>();
+    let groups = match policy {
+        CollisionPolicy::Ignore => (0..starts.len()).map(|i| vec![i]).collect(),
+        CollisionPolicy::Wait => {
+            let routes = alone
+                .iter()
+                .map(|patrol| &patrol.as_ref().unwrap().0.visited)
+                .collect::<Vec<_>>();
+            crossing_groups(&routes)
+        }
+    };
+
+    let mut guards = starts.iter().map(|_| None).collect::<Vec<_>>();
+    let mut ending = GroupEnding::AllLeft;
+    for group in groups {
+        let (patrols, group_ending) = match group[..] {
+            [i] => {
+                let (patrol, ending) = alone[i].take().unwrap();
+                (vec![patrol], ending)
+            }
+            _ => {
+                let starts = group.iter().map(|&i| starts[i]).collect::<Vec<_>>();
+                let patrol = lockstep(map, rule, &starts, policy, MAX_LOCKSTEP_STEPS);
+                (patrol.guards, patrol.ending)
+            }
+        };
+        for (i, patrol) in group.into_iter().zip(patrols) {
+            guards[i] = Some(patrol);
+        }
+        ending = ending.combine(group_ending);
+    }
+    let guards = guards.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+
+    // Until the last guard to leave has gone, nothing repeats
+    if let GroupEnding::Cycle {
+        cycle_start,
+        cycle_len,
+    } = ending
+    {
+        let last_exit = guards
+            .iter()
+            .filter_map(|guard| guard.left_at)
+            .max()
+            .unwrap_or(0);
+        ending = GroupEnding::Cycle {
+            cycle_start: cycle_start.max(last_exit),
+            cycle_len,
+        };
+    }
+    GroupPatrol { guards, ending }
+}
+
+/// Follows one guard with nobody else about, remembering the step at which
+/// they were first in each state so that a loop is found as soon as it
+/// closes. The ending is either `AllLeft` or `Cycle`.
+pub(super) fn patrol_alone(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    start: GuardState,
+) -> (GuardPatrol, GroupEnding) {
+    let mut first_step = vec![usize::MAX; map.num_cells() * 4];
+    let mut visited = vec![false; map.num_cells()];
+
+    let mut state = start;
+    let mut step = 0;
+    let (left_at, ending) = loop {
+        let seen = &mut first_step[map.state_index(state)];
+        if *seen != usize::MAX {
+            let ending = GroupEnding::Cycle {
+                cycle_start: *seen,
+                cycle_len: step - *seen,
+            };
+            break (None, ending);
+        }
+        *seen = step;
+        visited[map.index(state.0)] = true;
+
+        step += 1;
+        match next_guard_state(map, rule, state, None) {
+            Some(next) => state = next,
+            None => break (Some(step), GroupEnding::AllLeft),
+        }
+    };
+
+    let visited = (0..map.num_cells())
+        .filter(|&i| visited[i])
+        .map(|i| map.position(i))
+        .collect();
+    (GuardPatrol { visited, left_at }, ending)
+}
+
+/// Splits the guards into groups whose routes cross, directly or through
+/// other guards in the same group.
+fn crossing_groups(routes: &[&HashSet<(usize, usize)>]) -> Vec<Vec<usize>> {
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    let mut parent = (0..routes.len()).collect::<Vec<_>>();
+    let mut first_guard = HashMap::new();
+    for (i, route) in routes.iter().enumerate() {
+        for &cell in route.iter() {
+            let j = *first_guard.entry(cell).or_insert(i);
+            let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+            parent[a.max(b)] = a.min(b);
+        }
+    }
+
+    let mut groups = BTreeMap::<usize, Vec<usize>>::new();
+    for i in 0..routes.len() {
+        groups.entry(root(&mut parent, i)).or_default().push(i);
+    }
+    groups.into_values().collect()
+}
+
+/// Moves the guards together until every one has left or they repeat
+/// themselves, giving up after `max_steps` steps.
+///
+/// Loops are found with Brent's algorithm, which only ever keeps one earlier
+/// state of the guards to compare with, so however long the loop is the
+/// memory needed stays the same.
+fn lockstep(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    starts: &[GuardState],
+    policy: CollisionPolicy,
+    max_steps: usize,
+) -> GroupPatrol {
+    let mut guards = starts
+        .iter()
+        .map(|&(position, _)| GuardPatrol {
+            visited: HashSet::from([position]),
+            left_at: None,
+        })
+        .collect::<Vec<_>>();
+    let initial = starts.iter().copied().map(Some).collect::<Vec<_>>();
+
+    // The guards are recorded as they go, and by the time the loop is found
+    // they have been all the way round it
+    let mut states = initial.clone();
+    let mut earlier = initial.clone();
+    let (mut power, mut cycle_len) = (1, 0);
+    let mut step = 0;
+    loop {
+        step += 1;
+        tick(map, rule, policy, &mut states, |i, moved| match moved {
+            Some(position) => {
+                guards[i].visited.insert(position);
+            }
+            None => guards[i].left_at = Some(step),
+        });
+        cycle_len += 1;
+
+        if states.iter().all(Option::is_none) {
+            return GroupPatrol {
+                guards,
+                ending: GroupEnding::AllLeft,
+            };
+        }
+        if states == earlier {
+            break;
+        }
+        if step == max_steps {
+            return GroupPatrol {
+                guards,
+                ending: GroupEnding::NoRepeatWithin(max_steps),
+            };
+        }
+        if cycle_len == power {
+            earlier.clone_from(&states);
+            power *= 2;
+            cycle_len = 0;
+        }
+    }
+
+    // Two copies `cycle_len` steps apart first agree where the loop begins
+    let (mut behind, mut ahead) = (initial.clone(), initial);
+    for _ in 0..cycle_len {
+        tick(map, rule, policy, &mut ahead, |_, _| {});
+    }
+    let mut cycle_start = 0;
+    while behind != ahead {
+        tick(map, rule, policy, &mut behind, |_, _| {});
+        tick(map, rule, policy, &mut ahead, |_, _| {});
+        cycle_start += 1;
+    }
+
+    GroupPatrol {
+        guards,
+        ending: GroupEnding::Cycle {
+            cycle_start,
+            cycle_len,
+        },
+    }
+}
+
+/// Moves each guard still on the map one step, telling `moved` where each
+/// guard who moved went, or `None` if they walked off.
+fn tick(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    policy: CollisionPolicy,
+    states: &mut [Option<GuardState>],
+    mut moved: impl FnMut(usize, Option<(usize, usize)>),
+) {
+    let standing = states
+        .iter()
+        .flatten()
+        .map(|&(position, _)| position)
+        .collect::<Vec<_>>();
+    let mut claimed = Vec::with_capacity(states.len());
+
+    for (i, state) in states.iter_mut().enumerate() {
+        let Some(current) = *state else {
+            continue;
+        };
+        match next_guard_state(map, rule, current, None) {
+            None => {
+                *state = None;
+                moved(i, None);
+            }
+            Some(next)
+                if policy == CollisionPolicy::Wait
+                    && next.0 != current.0
+                    && (standing.contains(&next.0) || claimed.contains(&next.0)) =>
+            {
+                claimed.push(current.0);
+            }
+            Some(next) => {
+                claimed.push(next.0);
+                *state = Some(next);
+                moved(i, Some(next.0));
+            }
+        }
+    }
+}
+
+/// The least common multiple, or `None` if it doesn't fit in a `usize`.
+fn lcm(a: usize, b: usize) -> Option<usize> {
+    let gcd = |mut a: usize, mut b: usize| {
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        a
+    };
+    (a / gcd(a, b)).checked_mul(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{
+        lockstep, patrol_group, CollisionPolicy, GroupEnding, GroupPatrol, GuardPatrol,
+        MAX_LOCKSTEP_STEPS,
+    };
+    use crate::day6::{
+        find_exit,
+        movement::{Reverse, TurnRight},
+        obstacle_map::ObstacleMap,
+        parse_map, PatrolOutcome,
+    };
+
+    #[test]
+    fn head_on() {
+        let map = parse_map(">..<\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let guards = map.get_guards();
+
+        let waiting = patrol_group(&obstacles, &TurnRight, &guards, CollisionPolicy::Wait);
+        assert_eq!(
+            waiting,
+            GroupPatrol {
+                guards: vec![
+                    GuardPatrol {
+                        visited: HashSet::from([(0, 0), (1, 0)]),
+                        left_at: None
+                    },
+                    GuardPatrol {
+                        visited: HashSet::from([(3, 0), (2, 0)]),
+                        left_at: None
+                    },
+                ],
+                ending: GroupEnding::Cycle {
+                    cycle_start: 1,
+                    cycle_len: 1
+                },
+            }
+        );
+
+        let passing = patrol_group(&obstacles, &TurnRight, &guards, CollisionPolicy::Ignore);
+        assert_eq!(passing.ending, GroupEnding::AllLeft);
+        assert_eq!(passing.guards[0].left_at, Some(4));
+        assert_eq!(passing.guards[1].left_at, Some(4));
+        assert_eq!(passing.coverage().len(), 4);
+    }
+
+    #[test]
+    fn follow_the_leader() {
+        // The guard behind waits a step for the one in front to move off
+        let map = parse_map(">>..\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let patrol = patrol_group(
+            &obstacles,
+            &TurnRight,
+            &map.get_guards(),
+            CollisionPolicy::Wait,
+        );
+        assert_eq!(patrol.guards[0].left_at, Some(5));
+        assert_eq!(patrol.guards[1].left_at, Some(3));
+        assert_eq!(patrol.coverage().len(), 4);
+    }
+
+    #[test]
+    fn one_loops_one_leaves() {
+        let map = parse_map(".#..>\n.^.#.\n#....\n..#..\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let guards = map.get_guards();
+        assert_eq!(guards.len(), 2);
+
+        for policy in [CollisionPolicy::Ignore, CollisionPolicy::Wait] {
+            let patrol = patrol_group(&obstacles, &TurnRight, &guards, policy);
+            assert_eq!(
+                patrol.ending,
+                GroupEnding::Cycle {
+                    cycle_start: 1,
+                    cycle_len: 4
+                }
+            );
+            assert_eq!(patrol.guards[0].left_at, Some(1));
+            assert_eq!(patrol.guards[1].left_at, None);
+            assert_eq!(patrol.guards[1].visited.len(), 4);
+        }
+    }
+
+    #[test]
+    fn independent_cycles_combine() {
+        // Guards bouncing back and forth every four and every six steps
+        let map = parse_map("#>..##\n#>...#\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let guards = map.get_guards();
+
+        let combined = patrol_group(&obstacles, &Reverse, &guards, CollisionPolicy::Ignore);
+        let simulated = lockstep(
+            &obstacles,
+            &Reverse,
+            &guards,
+            CollisionPolicy::Ignore,
+            MAX_LOCKSTEP_STEPS,
+        );
+        assert_eq!(combined, simulated);
+        assert_eq!(
+            combined.ending,
+            GroupEnding::Cycle {
+                cycle_start: 1,
+                cycle_len: 12
+            }
+        );
+    }
+
+    #[test]
+    fn guards_who_never_meet_wait_for_nobody() {
+        // Bouncing every 14, 22, 26, 34, 38 and 46 steps, which only repeat
+        // together after millions of steps
+        let map = [7, 11, 13, 17, 19, 23]
+            .iter()
+            .map(|&len| format!("#>{}{}\n", ".".repeat(len), "#".repeat(24 - len)))
+            .collect::<String>();
+        let map = parse_map(map);
+        let obstacles = ObstacleMap::new(&map);
+        let guards = map.get_guards();
+
+        let waiting = patrol_group(&obstacles, &Reverse, &guards, CollisionPolicy::Wait);
+        assert_eq!(
+            waiting.ending,
+            GroupEnding::Cycle {
+                cycle_start: 1,
+                cycle_len: 2 * 7 * 11 * 13 * 17 * 19 * 23
+            }
+        );
+        assert_eq!(
+            waiting,
+            patrol_group(&obstacles, &Reverse, &guards, CollisionPolicy::Ignore)
+        );
+    }
+
+    #[test]
+    fn lockstep_gives_up() {
+        let map = parse_map("#>..##\n#>...#\n".to_string());
+        let obstacles = ObstacleMap::new(&map);
+        let guards = map.get_guards();
+        let patrol = lockstep(&obstacles, &Reverse, &guards, CollisionPolicy::Wait, 5);
+        assert_eq!(patrol.ending, GroupEnding::NoRepeatWithin(5));
+        assert_eq!(patrol.guards[1].visited.len(), 4);
+    }
+
+    #[test]
+    fn overlong_cycles() {
+        let cycle = |cycle_start, cycle_len| GroupEnding::Cycle {
+            cycle_start,
+            cycle_len,
+        };
+        assert_eq!(cycle(3, 4).combine(cycle(5, 6)), cycle(5, 12));
+        assert_eq!(
+            cycle(0, usize::MAX).combine(cycle(0, usize::MAX - 1)),
+            GroupEnding::CycleTooLong
+        );
+        assert_eq!(
+            GroupEnding::CycleTooLong.combine(GroupEnding::NoRepeatWithin(10)),
+            GroupEnding::NoRepeatWithin(10)
+        );
+        assert_eq!(GroupEnding::AllLeft.combine(cycle(1, 2)), cycle(1, 2));
+    }
+
+    #[test]
+    fn single_guard_matches_find_exit() {
+        let map = parse_map(
+            "....#.....\n.........#\n..........\n..#.......\n.......#..\n\
+             ..........\n.#..^.....\n........#.\n#.........\n......#...\n"
+                .to_string(),
+        );
+        let obstacles = ObstacleMap::new(&map);
+        let guards = map.get_guards();
+        let PatrolOutcome::Exited { visited } = find_exit(&obstacles, &TurnRight, guards[0]) else {
+            panic!("guard didn't leave");
+        };
+        for policy in [CollisionPolicy::Ignore, CollisionPolicy::Wait] {
+            let patrol = patrol_group(&obstacles, &TurnRight, &guards, policy);
+            assert_eq!(patrol.guards[0].visited, visited);
+            assert_eq!(patrol.ending, GroupEnding::AllLeft);
+        }
+    }
+}
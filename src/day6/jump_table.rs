@@ -0,0 +1,80 @@
+use super::{
+    obstacle_map::{Cell, ObstacleMap},
+    Direction,
+};
+
+/// Marks a cell from which the guard walks straight off the map.
+const EXIT: u32 = u32::MAX;
+
+/// For every cell and direction, the cell where the guard walking that way
+/// would stop in front of an obstacle or on a teleporter pad, so a patrol
+/// can go from turn to turn without visiting the cells in between.
+#[derive(Debug)]
+pub(crate) struct JumpTable<'a> {
+    map: &'a ObstacleMap,
+    /// Indexed by cell then direction
+    stops: Vec<u32>,
+}
+
+impl<'a> JumpTable<'a> {
+    pub(crate) fn new(map: &'a ObstacleMap) -> Self {
+        let num_cells = map.num_cells();
+        assert!(num_cells < EXIT as usize, "map too large for a jump table");
+
+        let mut stops = vec![EXIT; num_cells * 4];
+        for direction in [
+            Direction::Up,
+            Direction::Left,
+            Direction::Down,
+            Direction::Right,
+        ] {
+            // Each cell's stop follows from its neighbour's, which comes
+            // earlier in the map going up or left and later going down or
+            // right
+            let backwards = matches!(direction, Direction::Down | Direction::Right);
+            for i in 0..num_cells {
+                let cell = if backwards { num_cells - 1 - i } else { i };
+                let stop = match map.step(map.position(cell), direction) {
+                    None => EXIT,
+                    Some(next) if map.blocks(next, direction) => cell as u32,
+                    Some(next) if matches!(map.cell(next), Cell::Teleporter(_)) => {
+                        map.index(next) as u32
+                    }
+                    Some(next) => stops[map.state_index((next, direction))],
+                };
+                stops[cell * 4 + direction.index()] = stop;
+            }
+        }
+
+        Self { map, stops }
+    }
+
+    /// Where the guard walking `direction` from `position` stops in front of
+    /// an obstacle or on a teleporter pad, or `None` if they walk off the
+    /// map. One extra obstacle can be given, in an open cell, which is
+    /// checked here rather than built into the table.
+    pub(crate) fn jump(
+        &self,
+        position: (usize, usize),
+        direction: Direction,
+        extra_obstacle: Option<(usize, usize)>,
+    ) -> Option<(usize, usize)> {
+        let stop = match self.stops[self.map.state_index((position, direction))] {
+            EXIT => None,
+            cell => Some(self.map.position(cell as usize)),
+        };
+
+        let Some(distance) = extra_obstacle.and_then(|o| direction.steps_to(position, o)) else {
+            return stop;
+        };
+        let blocked_sooner = match stop {
+            None => true,
+            Some(stop) => direction.steps_to(position, stop).unwrap_or(0) >= distance,
+        };
+        if blocked_sooner {
+            Some(direction.advance(position, distance - 1))
+        } else {
+            stop
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use clap::ValueEnum;
+
+use super::{Direction, GuardState};
+
+/// What the guard does on finding their way blocked.
+///
+/// The answer must depend only on where the guard is and which way they're
+/// facing, so that a guard back in the same state is known to be in a loop.
+pub(crate) trait MovementRule: Sync {
+    /// The direction to face instead of `state`'s, whose way is blocked.
+    fn turn(&self, state: GuardState) -> Direction;
+}
+
+pub(crate) struct TurnRight;
+
+impl MovementRule for TurnRight {
+    fn turn(&self, (_, direction): GuardState) -> Direction {
+        direction.turn_right()
+    }
+}
+
+pub(crate) struct TurnLeft;
+
+impl MovementRule for TurnLeft {
+    fn turn(&self, (_, direction): GuardState) -> Direction {
+        direction.turn_right().turn_right().turn_right()
+    }
+}
+
+pub(crate) struct Reverse;
+
+impl MovementRule for Reverse {
+    fn turn(&self, (_, direction): GuardState) -> Direction {
+        direction.turn_right().turn_right()
+    }
+}
+
+/// Turns right, left or around, chosen by hashing the guard's state with a
+/// seed. The same seed always gives the same patrol.
+pub(crate) struct RandomTurn {
+    pub(crate) seed: u64,
+}
+
+impl MovementRule for RandomTurn {
+    fn turn(&self, ((x, y), direction): GuardState) -> Direction {
+        // splitmix64 finaliser over the seed and the state
+        let mut hash = self.seed
+            ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+            ^ direction.index() as u64;
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^= hash >> 31;
+
+        let quarter_turns = 1 + hash % 3;
+        (0..quarter_turns).fold(direction, |direction, _| direction.turn_right())
+    }
+}
+
+/// The movement rules which can be picked on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum Turn {
+    Right,
+    Left,
+    Reverse,
+    Random,
+}
+
+impl Turn {
+    /// The rule itself, with `seed` used only by `Turn::Random`.
+    pub(super) fn rule(self, seed: u64) -> Box<dyn MovementRule> {
+        match self {
+            Turn::Right => Box::new(TurnRight),
+            Turn::Left => Box::new(TurnLeft),
+            Turn::Reverse => Box::new(Reverse),
+            Turn::Random => Box::new(RandomTurn { seed }),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+use super::{Direction, GuardState, TileMap, TileType};
+
+/// What is in a cell of the lab, leaving aside the guard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Cell {
+    Open,
+    Obstacle,
+    /// Can only be walked onto going this way, and blocks the guard
+    /// otherwise
+    OneWay(Direction),
+    /// Walking onto it takes the guard straight to the other pad
+    Teleporter((usize, usize)),
+}
+
+/// The lab's obstacles and other fixtures, which never move, as one entry
+/// per cell. The guard is kept apart from this, so moving them doesn't touch
+/// the map at all.
+#[derive(Debug)]
+pub(crate) struct ObstacleMap {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+impl ObstacleMap {
+    pub(crate) fn new(map: &TileMap) -> Self {
+        let (width, height) = (map.0.rows(), map.0.cols());
+        let mut cells = vec![Cell::Open; width * height];
+        let mut pads = BTreeMap::<u8, Vec<(usize, usize)>>::new();
+        for ((x, y), tile) in map.0.indexed_iter() {
+            cells[y * width + x] = match *tile {
+                TileType::Obstacle => Cell::Obstacle,
+                TileType::OneWay(direction) => Cell::OneWay(direction),
+                TileType::Teleporter(label) => {
+                    pads.entry(label).or_default().push((x, y));
+                    Cell::Open
+                }
+                TileType::Guard(_) | TileType::Path => Cell::Open,
+            };
+        }
+
+        for (label, pads) in pads {
+            let [a, b] = pads[..] else {
+                panic!("teleporter {} needs exactly two pads", label as char);
+            };
+            cells[a.1 * width + a.0] = Cell::Teleporter(b);
+            cells[b.1 * width + b.0] = Cell::Teleporter(a);
+        }
+
+        Self {
+            width,
+            height,
+            cells,
+        }
+    }
+
+    pub(crate) fn num_cells(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Numbers the cells from 0 to `num_cells() - 1`, row by row.
+    pub(crate) fn index(&self, (x, y): (usize, usize)) -> usize {
+        y * self.width + x
+    }
+
+    /// Numbers the guard's states from 0 to `num_cells() * 4 - 1`, by cell
+    /// then direction.
+    pub(crate) fn state_index(&self, (position, direction): GuardState) -> usize {
+        self.index(position) * 4 + direction.index()
+    }
+
+    pub(crate) fn position(&self, index: usize) -> (usize, usize) {
+        (index % self.width, index / self.width)
+    }
+
+    pub(crate) fn cell(&self, position: (usize, usize)) -> Cell {
+        self.cells[self.index(position)]
+    }
+
+    /// Whether the guard can't walk onto `position` going `direction`.
+    pub(crate) fn blocks(&self, position: (usize, usize), direction: Direction) -> bool {
+        match self.cell(position) {
+            Cell::Obstacle => true,
+            Cell::OneWay(allowed) => allowed != direction,
+            Cell::Open | Cell::Teleporter(_) => false,
+        }
+    }
+
+    /// Where the guard ends up on walking onto `position`.
+    pub(crate) fn arrive(&self, position: (usize, usize)) -> (usize, usize) {
+        match self.cell(position) {
+            Cell::Teleporter(other) => other,
+            _ => position,
+        }
+    }
+
+    /// The neighbouring cell in the given direction, if it's on the map.
+    pub(crate) fn step(
+        &self,
+        position: (usize, usize),
+        direction: Direction,
+    ) -> Option<(usize, usize)> {
+        let (x, y) = direction.step(position)?;
+        (x < self.width && y < self.height).then_some((x, y))
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use super::{
+    movement::MovementRule, next_guard_state, obstacle_map::ObstacleMap, Direction, GuardState,
+    TileMap,
+};
+
+/// One cell of the guard's route.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TraceStep {
+    pub(crate) position: (usize, usize),
+    pub(crate) direction: Direction,
+    /// Whether the guard turns here before moving on
+    pub(crate) turn: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum TraceFormat {
+    Csv,
+    Json,
+}
+
+/// Follows the guard from `start` until they leave the map, or until the
+/// next step would repeat one already taken.
+pub(crate) fn trace(
+    map: &ObstacleMap,
+    rule: &dyn MovementRule,
+    start: GuardState,
+) -> Vec<TraceStep> {
+    let mut seen = vec![false; map.num_cells() * 4];
+    seen[map.state_index(start)] = true;
+
+    let mut steps = vec![TraceStep {
+        position: start.0,
+        direction: start.1,
+        turn: false,
+    }];
+    let mut state = start;
+    while let Some(next) = next_guard_state(map, rule, state, None) {
+        steps.last_mut().unwrap().turn = next.1 != state.1;
+        if seen[map.state_index(next)] {
+            break;
+        }
+        seen[map.state_index(next)] = true;
+        steps.push(TraceStep {
+            position: next.0,
+            direction: next.1,
+            turn: false,
+        });
+        state = next;
+    }
+    steps
+}
+
+pub(crate) fn format_trace(steps: &[TraceStep], format: TraceFormat) -> String {
+    match format {
+        TraceFormat::Csv => {
+            let mut out = "step,x,y,direction,turn\n".to_string();
+            for (i, step) in steps.iter().enumerate() {
+                let (x, y) = step.position;
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    i,
+                    x,
+                    y,
+                    step.direction.name(),
+                    step.turn
+                )
+                .unwrap();
+            }
+            out
+        }
+        TraceFormat::Json => {
+            let steps = steps
+                .iter()
+                .enumerate()
+                .map(|(i, step)| {
+                    let (x, y) = step.position;
+                    format!(
+                        "{{\"step\":{},\"x\":{},\"y\":{},\"direction\":\"{}\",\"turn\":{}}}",
+                        i,
+                        x,
+                        y,
+                        step.direction.name(),
+                        step.turn
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            format!("[{}]\n", steps)
+        }
+    }
+}
+
+/// Draws the route over the map the way the puzzle does: `|` and `-` where
+/// the guard walked up/down or left/right, and `+` where they turned or
+/// crossed their own path. The guard's starting cell, and any one-way tiles
+/// or teleporters, are left as they were.
+pub(crate) fn render(map: &TileMap, steps: &[TraceStep]) -> String {
+    let mut rows = map
+        .to_string()
+        .lines()
+        .map(|line| line.chars().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let start = steps.first().map(|step| step.position);
+    for step in steps.iter().filter(|step| Some(step.position) != start) {
+        let (x, y) = step.position;
+        let mark = match step.direction {
+            Direction::Up | Direction::Down => '|',
+            Direction::Left | Direction::Right => '-',
+        };
+        let cell = &mut rows[y][x];
+        *cell = match *cell {
+            '.' | '|' | '-' | '+' if step.turn => '+',
+            '.' => mark,
+            crossed @ ('|' | '-') if crossed != mark => '+',
+            other => other,
+        };
+    }
+    rows.iter()
+        .map(|row| row.iter().collect::<String>() + "\n")
+        .collect()
+}
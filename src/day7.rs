@@ -7,7 +7,7 @@ pub(crate) fn day7(input_filename: &str) {
     println!("sum of valid equation lhs: {}", sum);
 }
 
-fn sum_valid_equations(equations: &Vec<Equation>) -> u64 {
+fn sum_valid_equations(equations: &[Equation]) -> u64 {
     equations
         .iter()
         .filter(|eqn| eqn.is_valid())
@@ -62,6 +62,7 @@ impl Equation {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::{parse_equations, sum_valid_equations, Equation};
 
@@ -89,19 +90,19 @@ mod tests {
             lhs: 3267,
             rhs: vec![81, 40, 27],
         };
-        assert_eq!(eqn.is_valid(), true);
+        assert!(eqn.is_valid());
 
         let eqn = Equation {
             lhs: 190,
             rhs: vec![19, 10],
         };
-        assert_eq!(eqn.is_valid(), true);
+        assert!(eqn.is_valid());
 
         let eqn = Equation {
             lhs: 83,
             rhs: vec![17, 5],
         };
-        assert_eq!(eqn.is_valid(), false);
+        assert!(!eqn.is_valid());
     }
 
     #[test]
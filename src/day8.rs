@@ -16,8 +16,8 @@ pub(crate) fn day8(input_filename: &str) {
     );
 
     let antinodes = antennas
-        .iter()
-        .map(|(_, antennas)| compute_antinodes(antennas))
+        .values()
+        .map(|antennas| compute_antinodes(antennas))
         .reduce(|a1, a2| {
             let set: HashSet<_> = a1.union(&a2).map(|p| p.to_owned()).collect();
             set
@@ -30,10 +30,10 @@ pub(crate) fn day8(input_filename: &str) {
     println!("there are {} antinodes.", antinodes);
 }
 
-fn compute_antinodes(antennas: &Vec<(usize, usize)>) -> HashSet<(i64, i64)> {
+fn compute_antinodes(antennas: &[(usize, usize)]) -> HashSet<(i64, i64)> {
     let mut antinodes = HashSet::<(i64, i64)>::new();
 
-    let pairs = pairs(&antennas);
+    let pairs = pairs(antennas);
     for pair in pairs {
         // compute line equation through the two points
         let (x1, y1) = (pair.0 .0 as f64, pair.0 .1 as f64);
@@ -87,7 +87,7 @@ fn find_antennas(map: &Grid<char>) -> HashMap<char, Vec<(usize, usize)>> {
     antenna_index
 }
 
-fn pairs<T: PartialEq>(v: &Vec<T>) -> Vec<Pair<&T>> {
+fn pairs<T: PartialEq>(v: &[T]) -> Vec<Pair<&T>> {
     let mut vout = Vec::<Pair<&T>>::new();
 
     for i in 0..v.len() {
@@ -116,6 +116,7 @@ where
     }
 }
 
+#[cfg(test)]
 mod tests {
     use std::collections::HashSet;
 
@@ -142,8 +143,8 @@ mod tests {
         let antennas = find_antennas(&map);
         println!("{:#?}", antennas);
 
-        let pairs_of_A = pairs(antennas.get(&'A').unwrap());
-        println!("{:?}", pairs_of_A);
+        let pairs_of_a = pairs(antennas.get(&'A').unwrap());
+        println!("{:?}", pairs_of_a);
     }
 
     #[test]
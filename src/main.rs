@@ -1,3 +1,5 @@
+use std::num::NonZeroUsize;
+
 use clap::{Parser, Subcommand};
 
 mod day1;
@@ -8,6 +10,8 @@ mod day5;
 mod day6;
 mod day7;
 mod day8;
+#[cfg(test)]
+mod test_util;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,22 +30,116 @@ enum Commands {
     Day2 {
         /// Path to input data
         input_filename: String,
+
+        /// How many levels the Problem Dampener may remove from a report
+        #[arg(long, default_value_t = 1)]
+        damping: usize,
+
+        /// Print per-report diagnostics as a table (default) or JSON
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "table"
+        )]
+        explain: Option<day2::ExplainFormat>,
     },
     Day3 {
         /// Path to input data
         input_filename: String,
+
+        /// Also recognise add, sub and begin/end scope instructions
+        #[arg(long)]
+        extended: bool,
+
+        /// How to combine the values produced by instructions
+        #[arg(long, value_enum, default_value_t = day3::Accumulate::Sum)]
+        accumulate: day3::Accumulate,
+
+        /// List each instruction with its position and what it did
+        #[arg(long)]
+        trace: bool,
+
+        /// Print the input with recognised instructions coloured in
+        #[arg(long)]
+        highlight: bool,
     },
     Day4 {
         /// Path to input data
         input_filename: String,
+
+        /// Word to search for (may be given more than once)
+        #[arg(long = "word", default_value = "XMAS")]
+        words: Vec<String>,
+
+        /// 2D pattern to search for in any orientation, with rows separated
+        /// by `/` and `.` matching anything (may be given more than once)
+        #[arg(long = "pattern", default_value = "M.S/.A./M.S")]
+        patterns: Vec<String>,
+
+        /// List where each word was found and which way it reads
+        #[arg(long = "matches")]
+        list_matches: bool,
+
+        /// Print the grid showing only letters which are part of a match,
+        /// as plain text (default) or in colour
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "plain"
+        )]
+        show: Option<day4::Render>,
     },
     Day5 {
         /// Path to input data
         input_filename: String,
+
+        /// List the rules each update breaks, or its middle page if it is valid
+        #[arg(long)]
+        explain: bool,
+
+        /// Write the rules to this file as a Graphviz DOT digraph
+        #[arg(long = "dot")]
+        dot_filename: Option<String>,
+
+        /// Only draw the rules between the pages of this update (counting
+        /// from 0), highlighting the ones it breaks
+        #[arg(long, requires = "dot_filename")]
+        dot_update: Option<usize>,
     },
     Day6 {
         /// Path to input data
         input_filename: String,
+
+        /// How many threads to search for loop-causing obstructions on
+        /// (defaults to one per CPU)
+        #[arg(long)]
+        threads: Option<NonZeroUsize>,
+
+        /// Print every step of the guard's route, with the cells where they
+        /// turn, as CSV or JSON
+        #[arg(long, value_enum)]
+        trace: Option<day6::TraceFormat>,
+
+        /// Print the map with the guard's route drawn on it
+        #[arg(long = "show")]
+        show_route: bool,
+
+        /// Which way the guard turns on finding their way blocked
+        #[arg(long, value_enum, default_value_t = day6::Turn::Right)]
+        turn: day6::Turn,
+
+        /// Seed for `--turn random`
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// What guards do when they would walk into each other, on maps with
+        /// more than one
+        #[arg(long, value_enum, default_value_t = day6::CollisionPolicy::Wait)]
+        collisions: day6::CollisionPolicy,
     },
     Day7 {
         /// Path to input data
@@ -58,11 +156,53 @@ fn main() {
 
     match &cli.command {
         Commands::Day1 { input_filename } => day1::day1(input_filename),
-        Commands::Day2 { input_filename } => day2::day2(input_filename),
-        Commands::Day3 { input_filename } => day3::day3(input_filename),
-        Commands::Day4 { input_filename } => day4::day4(input_filename),
-        Commands::Day5 { input_filename } => day5::day5(input_filename),
-        Commands::Day6 { input_filename } => day6::day6(input_filename),
+        Commands::Day2 {
+            input_filename,
+            damping,
+            explain,
+        } => day2::day2(input_filename, *damping, *explain),
+        Commands::Day3 {
+            input_filename,
+            extended,
+            accumulate,
+            trace,
+            highlight,
+        } => day3::day3(input_filename, *extended, *accumulate, *trace, *highlight),
+        Commands::Day4 {
+            input_filename,
+            words,
+            patterns,
+            list_matches,
+            show,
+        } => day4::day4(input_filename, words, patterns, *list_matches, *show),
+        Commands::Day5 {
+            input_filename,
+            explain,
+            dot_filename,
+            dot_update,
+        } => day5::day5(
+            input_filename,
+            *explain,
+            dot_filename.as_deref(),
+            *dot_update,
+        ),
+        Commands::Day6 {
+            input_filename,
+            threads,
+            trace,
+            show_route,
+            turn,
+            seed,
+            collisions,
+        } => day6::day6(
+            input_filename,
+            *threads,
+            *trace,
+            *show_route,
+            *turn,
+            *seed,
+            *collisions,
+        ),
         Commands::Day7 { input_filename } => day7::day7(input_filename),
         Commands::Day8 { input_filename } => day8::day8(input_filename),
     }
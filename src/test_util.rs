@@ -0,0 +1,31 @@
+//! Helpers shared between the days' tests.
+//!
+//! Benchmarks are ignored tests named `bench_*`. Run them with
+//! `cargo test --release -- --ignored --nocapture bench_` to see timings.
+
+/// xorshift64, for repeatable pseudo-random test data. The same seed always
+/// gives the same sequence.
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> Self {
+        assert_ne!(seed, 0, "xorshift never leaves a zero state");
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number from 0 up to but not including `n`.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub(crate) fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}